@@ -1,13 +1,13 @@
-#[allow(clippy::upper_case_acronyms)]
+#![allow(clippy::upper_case_acronyms)]
 use std::{
-    io, net::IpAddr,
+    fs, io, net::IpAddr,
     time::Duration,
     thread::sleep};
 
-use blues::{TIMEOUT, PingStore, IPStore, Scanner, get_rt};
-use clap::{builder::ArgAction, Subcommand, Parser};
-use nbd::server::Blocks;
-use log::{trace, debug, info, error};
+use blues::{TIMEOUT, BATCH, PingStore, IPStore, Scanner, ScrubReport, control, get_rt};
+use clap::{builder::ArgAction, Subcommand, Parser, ValueEnum};
+use blues::nbd::{self, Blocks};
+use log::{trace, debug, info, warn, error};
 
 /// Blues "cloud" storage engine
 #[derive(Parser, Debug)]
@@ -22,6 +22,10 @@ struct Args {
     #[arg(short, long, value_parser, default_value = "ips.json")]
     file: String,
 
+    /// Packets sent or received per syscall, one for a syscall per packet
+    #[arg(short = 'B', long, value_parser, default_value_t = 32)]
+    batch: usize,
+
     /// Subcommands
     #[command(subcommand)]
     sub: Command,
@@ -56,12 +60,203 @@ enum Command {
 
     /// NBD server and client operations
     NBD {
-        /// NBD device path
+        /// NBD device to attach the first volume to, empty to only serve them over the network
         #[arg(short, long, value_parser, default_value = "/dev/nbd0")]
         device: String,
+
+        /// Address to serve every volume on by name to NBD clients
+        #[arg(short, long, value_parser, default_value = "127.0.0.1:10809")]
+        listen: String,
+
+        /// Longest ping timeout in ms, a replica not answering within the timeout estimated
+        /// from its round trip times, at most this, is considered lost
+        #[arg(short = 'o', long, value_parser, default_value_t = 7000)]
+        timeout: u64,
+
+        /// Matching replies (R) needed to complete a read, zero for a majority
+        #[arg(short, long, value_parser, default_value_t = 0)]
+        read_quorum: usize,
+
+        /// Echoed copies (W) needed to complete a write, zero for a majority
+        #[arg(short, long, value_parser, default_value_t = 0)]
+        write_quorum: usize,
+
+        /// Acknowledge writes once cached, reaching the write quorum on flush
+        #[arg(short = 'b', long, action = ArgAction::SetTrue)]
+        writeback: bool,
+
+        /// Blocks of a stripe, placed on disjoint destinations and pinged in parallel,
+        /// one sends them one after another
+        #[arg(long, value_parser, default_value_t = 64)]
+        stripe: usize,
+
+        /// How many ms between checks of every block for replicas that stopped answering, zero
+        /// disables it, the scrubber already repairs every block it walks
+        #[arg(short = 'p', long, value_parser, default_value_t = 0)]
+        repair: u64,
+
+        /// Blocks per second the scrubber verifies and repairs, zero disables it
+        #[arg(short, long, value_parser, default_value_t = 10.0)]
+        scrub: f64,
+
+        /// Seconds between moves of hot blocks onto fast destinations and cold blocks onto
+        /// slow ones, zero, the default, disables tiering
+        #[arg(short, long, value_parser, default_value_t = 0)]
+        tier: u64,
+
+        /// Most blocks moved between tiers each time
+        #[arg(long, value_parser, default_value_t = 64)]
+        tier_moves: usize,
+
+        /// Where the scrubber keeps its report
+        #[arg(long, value_parser, default_value = "scrub.json")]
+        report: String,
+
+        /// Size of the device in bytes, may exceed the capacity of the destinations
+        /// as blocks of zeroes take up none, zero for the capacity of the destinations, which
+        /// only a single volume may have
+        #[arg(short = 'z', long, value_parser, default_value_t = 0)]
+        size: u64,
+
+        /// Where to keep the local copy of the volume superblock
+        #[arg(long, value_parser, default_value = "volume.json")]
+        superblock: String,
+
+        /// Describe the volume with a new superblock, even if the destinations changed
+        #[arg(long, action = ArgAction::SetTrue)]
+        format: bool,
+
+        /// Where to keep the map of which destinations hold every block
+        #[arg(long, value_parser, default_value = "placement.json")]
+        placement: String,
+
+        /// Unix socket to take commands like snapshots on
+        #[arg(short, long, value_parser, default_value = "blues.sock")]
+        control: String,
+
+        /// File whose contents the blocks of volumes are encrypted with, empty to store them in the clear
+        #[arg(long, value_parser, default_value = "")]
+        key: String,
+
+        /// Volume to export as NAME[:SIZE[:COPIES[:READ_QUORUM[:WRITE_QUORUM[:KEY]]]]], may be
+        /// repeated, parts left out are taken from the options above, defaults to a single volume
+        /// "default"
+        #[arg(long = "volume", value_parser = parse_volume)]
+        volumes: Vec<Volume>,
+
+        /// What to do with destinations added to the save file while running
+        #[arg(short, long, value_enum, default_value_t = Ingest::Off)]
+        ingest: Ingest,
+    },
+
+    /// List the volumes exported by a running NBD server
+    Volumes {
+        /// Unix socket the NBD server takes commands on
+        #[arg(short, long, value_parser, default_value = "blues.sock")]
+        control: String,
+    },
+
+    /// Manage copy-on-write snapshots of a volume of a running NBD server
+    Snapshot {
+        /// Unix socket the NBD server takes commands on
+        #[arg(short, long, value_parser, default_value = "blues.sock")]
+        control: String,
+
+        /// Volume to manage the snapshots of
+        #[arg(short, long, value_parser, default_value = "default")]
+        volume: String,
+
+        #[command(subcommand)]
+        op: SnapshotOp,
+    },
+
+    /// Compare the echo throughput of batched and per-packet sending and receiving
+    Bench {
+        /// Reflector to bounce pings off, the kernel answers them on loopback
+        #[arg(short, long, value_parser, default_value = "127.0.0.1")]
+        ip: IpAddr,
+
+        /// Pings sent for each measurement
+        #[arg(short = 'n', long, value_parser, default_value_t = 100000)]
+        count: usize,
+
+        /// Ping timeout in ms
+        #[arg(short = 'o', long, value_parser, default_value_t = 1000)]
+        timeout: u64,
+
+        /// Concurrent readers of a loopback store, and threads looking up block state, to compare
+        /// against a single one, zero skips both
+        #[arg(short, long, value_parser, default_value_t = 8)]
+        readers: usize,
+
+        /// Blocks the readers read back between them
+        #[arg(short, long, value_parser, default_value_t = 256)]
+        blocks: usize,
+    },
+
+    /// Show the report kept by the scrubber of the NBD server
+    Scrub {
+        /// Where the scrubber keeps its report
+        #[arg(long, value_parser, default_value = "scrub.json")]
+        report: String,
+    },
+}
+
+/// Settings of a single exported volume, zero means the defaults
+#[derive(Clone, Debug)]
+struct Volume {
+    name: String,
+    size: u64,
+    copies: usize,
+    read_quorum: usize,
+    write_quorum: usize,
+    key: String, // File holding the key
+}
+
+/// Parse NAME[:SIZE[:COPIES[:READ_QUORUM[:WRITE_QUORUM[:KEY]]]]]
+fn parse_volume(spec: &str) -> Result<Volume, String> {
+    let mut parts = spec.split(':');
+    let name = parts.next().filter(|name| !name.is_empty())
+        .ok_or("volume needs a name")?.to_string();
+    let mut num = || -> Result<usize, String> {
+        parts.next().filter(|part| !part.is_empty())
+            .map_or(Ok(0), |part| part.parse().map_err(|err| format!("\"{part}\": {err}")))
+    };
+    let (size, copies, read_quorum, write_quorum) = (num()? as u64, num()?, num()?, num()?);
+    let key = parts.next().unwrap_or_default().to_string();
+    Ok(Volume { name, size, copies, read_quorum, write_quorum, key })
+}
+
+/// `file` for the volume `name`, prefixed by the name unless it's the default volume
+fn named(name: &str, file: &str) -> String {
+    match name {
+        "default" => file.to_string(),
+        name => format!("{name}-{file}"),
     }
 }
 
+/// Snapshot operations (enum)
+#[derive(Subcommand, Debug)]
+enum SnapshotOp {
+    /// Freeze the volume as it is now
+    Create { name: String },
+    /// Show the snapshots taken
+    List,
+    /// Return the volume to a snapshot
+    Rollback { name: String },
+}
+
+/// Handling of new destinations (enum)
+#[derive(ValueEnum, Clone, Debug)]
+enum Ingest {
+    /// Leave them alone until restart
+    Off,
+    /// Grow the device with new blocks
+    Grow,
+    /// Move replicas onto them where they're faster
+    Rebalance,
+}
+
 fn main() -> io::Result<()> {
     let args = Args::parse();
 
@@ -72,6 +267,7 @@ fn main() -> io::Result<()> {
     info!("Starting blues");
     trace!("With args: {:#?}", args);
 
+    unsafe { BATCH = args.batch };
     match args.sub {
         Command::Recon { throttle, parallel, timeout, limit, rand } => {
             debug!("Mode is Scan/Recon");
@@ -90,28 +286,128 @@ fn main() -> io::Result<()> {
             IPStore::from_scanner(&scanner).save(&args.file);
         },
 
-        Command::NBD { device } => {
+        Command::NBD {
+            device, listen, timeout, read_quorum, write_quorum, writeback, stripe, repair, scrub, tier,
+            tier_moves, report, size, superblock, format, placement, control, key, volumes, ingest
+        } => {
             debug!("Mode is NBD");
-            let store = PingStore::load_clients(&args.file);
-            let mut data = vec![
-                0x49, 0x43, 0x4d, 0x50, 0x20, 0x62, 0x61, 0x6c,
-                0x6c, 0x65, 0x20, 0x6e, 0x65, 0x67, 0x65, 0x72];
-//            get_rt("blues NBD driver", args.threads).spawn(store.run());
-            data.append(&mut [0x66; 64].to_vec());
-//            data.append(&mut [0x66; 48].to_vec());
-            info!("Storing some data in a few pings!");
-            store.write_at(&data, 0)?;
-//            info!("Waiting for data corruption!");
-//            sleep(Duration::from_millis(3000));
-            let mut res: [u8; 64 + 16] = [0; 64 + 16];
-//            let mut res: [u8; 64] = [0; 64];
-            store.read_at(&mut res, 0)?;
-            if res.to_vec() == data {
-                info!("SUCCESS! Recived same data as stored!");
-            } else {
-                error!("Didn't recive the same data as stored!\nExpected: {data:?}\nGot: {res:?}");
+            unsafe { TIMEOUT = Some(Duration::from_millis(timeout)) };
+            let specs = match volumes.is_empty() {
+                true => vec![Volume {
+                    name: "default".to_string(), size, copies: 0, read_quorum, write_quorum, key: String::new(),
+                }],
+                false => volumes,
+            };
+            // A volume without a size tracks the capacity of the destinations, which several
+            // of them sharing it would each claim whole
+            if let Some(spec) = specs.iter().find(|spec| specs.len() > 1 && spec.size == 0 && size == 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("volume \"{}\" needs a size when several volumes are served", spec.name)));
             }
-        }
+            let pool = PingStore::load_clients(&args.file, &placement);
+            let first = specs[0].name.clone();
+            let mut volumes = control::Volumes::new();
+            let mut advertised = 0;
+            for spec in specs {
+                let mut volume = pool.volume(&spec.name)
+                    .read_quorum(if spec.read_quorum > 0 { spec.read_quorum } else { read_quorum })
+                    .write_quorum(if spec.write_quorum > 0 { spec.write_quorum } else { write_quorum })
+                    .writeback(writeback)
+                    .stripe(stripe)
+                    .capacity(if spec.size > 0 { spec.size } else { size });
+                if spec.copies > 0 {
+                    volume = volume.copies(spec.copies);
+                }
+                let key = if spec.key.is_empty() { &key } else { &spec.key };
+                if !key.is_empty() {
+                    volume = volume.key(&fs::read(key)?);
+                }
+                let volume = volume.mount(&named(&spec.name, &superblock), format)?;
+                if repair > 0 {
+                    volume.repairer(Duration::from_millis(repair));
+                }
+                if scrub > 0.0 {
+                    volume.scrubber(scrub, named(&spec.name, &report));
+                }
+                if tier > 0 {
+                    volume.tierer(Duration::from_secs(tier), tier_moves);
+                }
+                info!("Exporting volume \"{}\" of {} bytes", spec.name, Blocks::size(&volume)?);
+                advertised += Blocks::size(&volume)?;
+                volumes.insert(spec.name, volume);
+            }
+            if advertised > pool.physical() {
+                warn!("Volumes export {advertised} bytes, more than the {} the destinations can hold",
+                    pool.physical());
+            }
+            // Destinations added since the placement map was saved only join once the volumes
+            // are mounted against it
+            pool.ingest(&args.file, !matches!(ingest, Ingest::Rebalance));
+            pool.placer(Duration::from_secs(1));
+            control::serve(volumes.clone(), &control)?;
+            match ingest {
+                Ingest::Off => (),
+                Ingest::Grow => { pool.ingester(args.file.clone(), Duration::from_secs(10), true); },
+                Ingest::Rebalance => { pool.ingester(args.file.clone(), Duration::from_secs(10), false); },
+            }
+            nbd::serve(volumes.clone(), &listen)?;
+            if !device.is_empty() {
+                match nbd::attach(&device, volumes[&first].clone()) {
+                    Err(err) => error!("Attaching volume \"{first}\" to \"{device}\": {err}"),
+                    Ok(_) => info!("Attached volume \"{first}\" to \"{device}\""),
+                }
+            }
+            loop {
+                sleep(Duration::from_secs(60));
+                for (name, volume) in volumes.iter() {
+                    debug!("Volume \"{name}\" has {} blocks holding data in {} slots", volume.mapped(), volume.allocated());
+                }
+                trace!("Saving destination states to file: {}", args.file);
+                pool.save(&args.file);
+            }
+        },
+
+        Command::Volumes { control } => {
+            debug!("Mode is Volumes");
+            print!("{}", control::request(&control, "volume list")?);
+        },
+
+        Command::Snapshot { control, volume, op } => {
+            debug!("Mode is Snapshot");
+            let command = match op {
+                SnapshotOp::Create { name } => format!("snapshot {volume} create {name}"),
+                SnapshotOp::List => format!("snapshot {volume} list"),
+                SnapshotOp::Rollback { name } => format!("snapshot {volume} rollback {name}"),
+            };
+            print!("{}", control::request(&control, &command)?);
+        },
+
+        Command::Bench { ip, count, timeout, readers, blocks } => {
+            debug!("Mode is Bench");
+            unsafe { TIMEOUT = Some(Duration::from_millis(timeout)) };
+            for batch in [1, args.batch] {
+                let (rate, lost) = blues::throughput(ip, count, batch);
+                println!("batch {batch:>4}: {rate:>12.0} echoes/s, {lost} lost");
+            }
+            if readers > 0 {
+                for readers in [1, readers] {
+                    let rate = blues::read_throughput(blocks, readers)?;
+                    println!("readers {readers:>2}: {rate:>10.0} blocks/s");
+                }
+                println!("on {} CPUs, block state lookups as a read makes them:", num_cpus::get());
+                for (global, name) in [(true, "one lock"), (false, "sharded")] {
+                    for threads in [1, readers] {
+                        let rate = blues::state_throughput(blocks, threads, global);
+                        println!("{name:>8}, threads {threads:>2}: {rate:>10.0} lookups/s");
+                    }
+                }
+            }
+        },
+
+        Command::Scrub { report } => {
+            debug!("Mode is Scrub");
+            println!("{:#?}", ScrubReport::load(&report));
+        },
     }
     Ok(())
 }
@@ -1,41 +1,194 @@
 use std::{
-    sync::{Mutex, Arc}, time::Duration, thread::sleep,
-    net::{Ipv4Addr, IpAddr}, vec::Vec, io};
-
-use log::{trace, warn, error};
-use tokio::{
-    sync::mpsc,
-    runtime, task};
-use nbd::server::Blocks;
-use icmp::IcmpSocket;
+    sync::{Mutex, Arc}, time::{Duration, Instant}, thread::{self, sleep},
+    collections::{BTreeMap, HashMap, HashSet},
+    cmp::Reverse, ops::Range, future::Future, fs, mem,
+    net::IpAddr, vec::Vec, io};
+
+use log::{trace, debug, info, warn, error};
+use tokio::{sync::{mpsc, Mutex as AsyncMutex, OwnedMutexGuard}, task};
+use crate::nbd::Blocks;
+use rand::random;
 use crate::{
-    ICMP_PACKET, BYTE_COUNT, PACKET_SIZE, SIZE,
-    IPStore, checksum, connect, get_rt};
+    SIZE,
+    buf::Buf,
+    cache::Dirty,
+    map::{self, BlockMap, Allocator, Hash},
+    cipher::Cipher,
+    scrub::ScrubReport,
+    lifecycle::{State, Event, PROBATION},
+    superblock::{self, Superblock, RESERVED},
+    placement::Placement,
+    json,
+    rto,
+    slots::Slots,
+    socket::Socket,
+    IPStore, Reputation, runtime};
 
-type Message = (usize, usize, Vec<u8>); // Message type for the write channel
+const COPIES: usize = 7; // Replicas of every block
+const STRIPE: usize = 4096 / SIZE; // Blocks of a stripe, in flight at once
 
 pub struct Ping {
-    socks: Vec<IcmpSocket>,
+    socks: Vec<Arc<Socket>>,
     ips: Vec<IpAddr>,
     copies: usize,
+    pinging: Arc<AsyncMutex<()>>, // Held by whoever is pinging the slot, see `PingStore::hold`
+}
+
+type Reply = (usize, u64, io::Result<Buf>); // Replica index, generation and payload of an echo
+type Described = Arc<Mutex<Option<(String, Superblock)>>>; // Superblock of a volume and its file
+
+/// Replicas of a block fixed by `PingStore::repair`
+#[derive(Default, Debug)]
+pub struct Repair {
+    /// Copies diverging from the quorum that were overwritten
+    pub corrected: usize,
+    /// Copies that stopped answering and were re-seeded onto spares
+    pub restored: usize,
 }
 
+#[derive(Clone)]
 pub struct PingStore {
-    pings: Arc<Mutex<Vec<Ping>>>,
-    size: u64,
+    pings: Arc<Slots<Ping>>,
+    spares: Arc<Mutex<Vec<IpAddr>>>,
+    known: Arc<Mutex<HashSet<IpAddr>>>,
+    superblock: Described,
+    described: Arc<Mutex<Vec<PingStore>>>, // Every mounted volume, its superblock kept matching the placement
+    placement: Option<String>,
+    placed: Arc<Mutex<Vec<u8>>>, // Placement map last saved
+    alloc: Arc<Allocator>,
+    maps: Arc<Mutex<BTreeMap<String, Arc<BlockMap>>>>, // Block maps of every volume
+    rtts: Arc<Mutex<HashMap<IpAddr, Duration>>>,
+    map: Arc<BlockMap>,
+    dirty: Arc<Dirty>,
+    pub reputation: Reputation,
+    read_quorum: usize,
+    write_quorum: usize,
+    writeback: bool,
+    blocks: usize,
+    copies: usize,
+    stripe: usize,
+    cipher: Option<Cipher>, // Key the blocks of the volume are encrypted with
 }
 
 impl PingStore {
     pub fn new() -> Self {
+        let alloc = Arc::new(Allocator::default());
         Self {
-            pings: Arc::new(Mutex::new(vec![])),
-            size: 0,
+            pings: Arc::new(Slots::default()),
+            spares: Arc::new(Mutex::new(vec![])),
+            known: Arc::new(Mutex::new(HashSet::new())),
+            superblock: Arc::new(Mutex::new(None)),
+            described: Arc::new(Mutex::new(vec![])),
+            placement: None,
+            placed: Arc::new(Mutex::new(vec![])),
+            maps: Arc::new(Mutex::new(BTreeMap::new())),
+            map: Arc::new(BlockMap::with_allocator(alloc.clone(), "default")),
+            alloc,
+            rtts: Arc::new(Mutex::new(HashMap::new())),
+            dirty: Arc::new(Dirty::default()),
+            reputation: Reputation::new(),
+            read_quorum: 0,
+            write_quorum: 0,
+            writeback: false,
+            blocks: 0,
+            copies: COPIES,
+            stripe: STRIPE,
+            cipher: None,
         }
     }
 
-    pub fn load_clients(file: &str) -> Self {
-        let ips = IPStore::load(file);
+    /// A new volume `name` sharing the destinations and physical slots of this one,
+    /// with the blocks recorded for it in the placement map if there are any
+    pub fn volume(&self, name: &str) -> Self {
+        let map = self.maps.lock().unwrap().entry(name.to_string())
+            .or_insert_with(|| Arc::new(BlockMap::with_allocator(self.alloc.clone(), name)))
+            .clone();
+        Self {
+            map,
+            dirty: Arc::new(Dirty::default()),
+            superblock: Arc::new(Mutex::new(None)),
+            read_quorum: 0,
+            write_quorum: 0,
+            writeback: false,
+            blocks: 0,
+            copies: COPIES,
+            cipher: None,
+            ..self.clone()
+        }
+    }
+
+    /// Replicas kept of every block, at most as many as there are destinations in a slot
+    pub fn copies(mut self, copies: usize) -> Self {
+        self.copies = copies.clamp(1, COPIES);
+        self
+    }
+
+    /// Matching replies (R) needed before a read returns, zero means a majority of the copies
+    pub fn read_quorum(mut self, quorum: usize) -> Self {
+        self.read_quorum = quorum;
+        self
+    }
+
+    /// Echoed copies (W) needed before a write returns, zero means a majority of the copies
+    pub fn write_quorum(mut self, quorum: usize) -> Self {
+        self.write_quorum = quorum;
+        self
+    }
+
+    /// Return from writes once they're cached, leaving the write quorum to `flush`
+    pub fn writeback(mut self, writeback: bool) -> Self {
+        self.writeback = writeback;
+        self
+    }
+
+    /// Consecutive blocks of a stripe, given slots on disjoint groups of destinations and
+    /// pinged and collected in parallel, one means sequentially
+    pub fn stripe(mut self, stripe: usize) -> Self {
+        self.stripe = stripe.max(1);
+        self
+    }
+
+    /// Encrypt the blocks of the volume with a key derived from `secret`, the superblock and
+    /// the placement map stay readable without it
+    pub fn key(mut self, secret: &[u8]) -> Self {
+        self.cipher = Some(Cipher::new(secret));
+        self
+    }
+
+    /// Size of the exported device in bytes, which may exceed the physical capacity
+    /// as only blocks holding data take up a slot, zero means the physical capacity
+    pub fn capacity(mut self, size: u64) -> Self {
+        self.blocks = (size as usize).div_ceil(SIZE);
+        self
+    }
+
+    /// Load the destinations in the IPStore `file`, the slots keep the replicas recorded in
+    /// the placement map `placement` if there is one, leaving the destinations it doesn't
+    /// place to be ingested once the volumes are mounted against it, otherwise they're built
+    /// in file order
+    pub fn load_clients(file: &str, placement: &str) -> Self {
         let mut store = Self::new();
+        store.placement = Some(placement.to_string());
+        let Some(placement) = Placement::load(placement) else {
+            store.ingest(file, true);
+            return store
+        };
+        for ips in placement.slots {
+            let mut ping = Ping::new();
+            for ip in ips {
+                ping.add(ip);
+            }
+            store.pings.push(ping);
+        }
+        store.alloc.replace(placement.alloc);
+        *store.maps.lock().unwrap() = placement.volumes.into_iter().map(|(name, map)| {
+            let map = map.restore(store.alloc.clone(), &name);
+            (name, Arc::new(map))
+        }).collect();
+        info!("Placed {} slots from the placement map", store.pings.len());
+        // Growing the store now would change the placement the superblocks were written against
+        let placed = store.placed_ips();
+        store.admit(IPStore::load(file), |ip| placed.contains(ip));
 /*
         let mut dstmap: Vec<(usize, IpAddr)> = vec![];
         for dst in ips.dsts {
@@ -44,102 +197,1124 @@ impl PingStore {
         let sorted = dstmap.sort_by(|a, b| a.0.cmp(&b.0));
         trace!("{:?}", sorted);
 */
-        for _ in 0..ips.dsts.len().div_floor(7) {
-            let offset = store.pings.lock().unwrap().len() * 7;
+        store
+    }
+
+    /// Destinations of every slot
+    fn placed_ips(&self) -> HashSet<IpAddr> {
+        self.pings.map(|ping| ping.ips.clone()).into_iter().flatten().collect()
+    }
+
+    /// Take in the destinations of `ips` not seen before that `admitted` lets in, with their
+    /// round trip times and lifecycle states, returning those not placed yet nor retired
+    fn admit(&self, ips: IPStore, admitted: impl Fn(&IpAddr) -> bool) -> Vec<IpAddr> {
+        let placed = self.placed_ips();
+        let mut known = self.known.lock().unwrap();
+        let mut rtts = self.rtts.lock().unwrap();
+        let mut fresh = vec![];
+        for dst in ips.dsts {
+            let ip = IpAddr::V4(dst.ip);
+            if !admitted(&ip) || !known.insert(ip) { continue }
+            rtts.insert(ip, dst.round_trip);
+            rto::seed(ip, dst.round_trip);
+            self.reputation.set_state(ip, dst.state);
+            if dst.state != State::Retired && !placed.contains(&ip) {
+                fresh.push(ip);
+            }
+        }
+        fresh
+    }
+
+    /// Pick up destinations in the IPStore `file` not seen before, either adding them as
+    /// new groups growing the store or moving replicas off the slowest destinations
+    pub fn ingest(&self, file: &str, grow: bool) -> usize {
+        let fresh = self.admit(IPStore::load(file), |_| true);
+        let count = fresh.len();
+        if count == 0 { return 0 }
+
+        let untrusted: Vec<IpAddr> = fresh.iter().copied()
+            .filter(|ip| !self.reputation.state(ip).trusted()).collect();
+        if !untrusted.is_empty() {
+            info!("Putting {} untrusted destinations through probation", untrusted.len());
+            runtime().block_on(self.probe(&untrusted, PROBATION));
+        }
+        let (trusted, untrusted): (Vec<IpAddr>, Vec<IpAddr>) = fresh.into_iter()
+            .partition(|ip| self.reputation.state(ip).trusted());
+        self.spares.lock().unwrap().splice(0..0, untrusted);
+        if grow { self.grow(trusted) } else { self.rebalance(trusted) }
+        count
+    }
+
+    /// Check the volume against the superblock saved in `file` and the one in its reserved
+    /// slots, adopting its settings, or describe the volume with a new superblock if it has
+    /// none or `format` is set, the superblock is then written into the reserved slots of the
+    /// volume and read back
+    pub fn mount(mut self, file: &str, format: bool) -> io::Result<Self> {
+        let dsts = self.placed();
+        let reclaimed = self.map.reclaim();
+        if reclaimed > 0 {
+            info!("Reclaimed {reclaimed} slots the volume no longer refers to");
+        }
+        // A volume whose reserved slots are in the placement map was written before
+        let written = !self.map.reserved().is_empty();
+        let physical = self.pings.len();
+        if self.map.reserve(RESERVED, physical).is_none() {
+            return Err(io::Error::from_raw_os_error(28)); // ENOSPC
+        }
+        let stored = match written && !format {
+            true => Some(runtime().block_on(self.read_superblock()).map_err(|err| io::Error::new(
+                err.kind(), format!("reading the superblock out of the reserved slots: {err}, \
+                    formatting describes the volume anew")))?),
+            false => None,
+        };
+        let superblock = match Superblock::load(file).or(stored.clone()) {
+            Some(superblock) if !format => {
+                let mismatch = |what: &str| Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{what} doesn't match superblock of volume {} in \"{file}\"", superblock.uuid)));
+                if stored.as_ref().is_some_and(|stored| *stored != superblock) {
+                    return mismatch("superblock in the reserved slots")
+                }
+                if superblock.version != superblock::VERSION { return mismatch("version") }
+                if superblock.block_size != SIZE { return mismatch("block size") }
+                if superblock.copies != self.copies { return mismatch("replica count") }
+                if superblock.dsts != dsts { return mismatch("placement map") }
+                if self.blocks != 0 && self.blocks != superblock.blocks { return mismatch("size") }
+                if superblock.key != self.fingerprint() { return mismatch("key") }
+                self.blocks = superblock.blocks;
+                if self.read_quorum == 0 { self.read_quorum = superblock.read_quorum }
+                if self.write_quorum == 0 { self.write_quorum = superblock.write_quorum }
+                info!("Mounting volume {}", superblock.uuid);
+                superblock
+            },
+            _ => {
+                let superblock = Superblock {
+                    key: self.fingerprint(),
+                    ..Superblock::new(self.blocks, self.copies, self.read_quorum, self.write_quorum, dsts)
+                };
+                info!("Formatting new volume {}", superblock.uuid);
+                superblock
+            },
+        };
+        *self.superblock.lock().unwrap() = Some((file.to_string(), superblock.clone()));
+        runtime().block_on(self.commit_superblock())?;
+        if runtime().block_on(self.read_superblock())? != superblock {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("superblock read back from volume {} doesn't match \"{file}\"", superblock.uuid)));
+        }
+        self.described.lock().unwrap().push(self.clone());
+        self.save_placement()?;
+        Ok(self)
+    }
+
+    /// Save the superblock locally and write it into the reserved slots
+    async fn commit_superblock(&self) -> io::Result<()> {
+        let Some((file, mut superblock)) = self.superblock.lock().unwrap().clone() else {
+            return Ok(())
+        };
+        superblock.dsts = self.placed();
+        superblock.save(&file);
+        let reserved = self.map.reserved().to_vec();
+        for (slot, data) in reserved.into_iter().zip(superblock.encode()?) {
+            let data = Buf::copy(&data);
+            let held = self.hold(slot).await;
+            let gen = self.dirty.mark(slot, &data);
+            let res = self.ping(slot, &data, held).await;
+            self.dirty.settle(slot, gen, &res);
+            res?;
+        }
+        *self.superblock.lock().unwrap() = Some((file, superblock));
+        Ok(())
+    }
+
+    /// Fingerprint of the key of the volume, zero if its blocks aren't encrypted
+    fn fingerprint(&self) -> u64 {
+        self.cipher.as_ref().map_or(0, Cipher::fingerprint)
+    }
+
+    /// Checksum of the placement map, the destinations of every slot in order
+    fn placed(&self) -> u64 {
+        superblock::checksum(0, &self.pings.map(|ping| ping.ips.clone()).concat())
+    }
+
+    /// Read the superblock back out of the reserved slots
+    pub async fn read_superblock(&self) -> io::Result<Superblock> {
+        let mut data = vec![];
+        let reserved = self.map.reserved().to_vec();
+        for slot in reserved {
+            data.extend_from_slice(&self.read_slot(slot).await?);
+        }
+        Superblock::decode(&data)
+    }
+
+    /// Add new groups of destinations, the ones not filling a group become spares
+    fn grow(&self, fresh: Vec<IpAddr>) {
+        let groups = fresh.chunks_exact(COPIES);
+        self.spares.lock().unwrap().splice(0..0, groups.remainder().iter().rev().copied());
+        for group in groups {
             let mut ping = Ping::new();
-            for j in 0..7 {
-                ping.add(IpAddr::V4(ips.dsts[offset + j].ip));
+            for ip in group {
+                ping.add(*ip);
             }
-            store.pings.lock().unwrap().push(ping);
-            store.size += 1;
+            self.pings.push(ping);
         }
-        store
+        info!("Grew store to {} blocks", self.pings.len());
+        if let Err(err) = self.save_placement() {
+            error!("Saving placement map after growing: {err:?}");
+        }
+    }
+
+    /// Record the replicas of every slot, the volume it's handed out to and the blocks of
+    /// every volume in the placement map, unless nothing changed since it was last saved
+    pub fn save_placement(&self) -> io::Result<()> {
+        let Some(file) = &self.placement else { return Ok(()) };
+        let placement = {
+            // Every map and the allocator are copied together so they agree on the slots in use
+            let maps = self.maps.lock().unwrap();
+            let (copies, alloc) = map::frozen(&maps.values().map(|map| &**map).collect::<Vec<_>>(), &self.alloc);
+            Placement {
+                slots: self.pings.map(|ping| ping.ips.clone()),
+                alloc,
+                volumes: maps.keys().cloned().zip(copies).collect(),
+            }
+        };
+        let data = serde_json::to_vec(&placement)?;
+        let mut placed = self.placed.lock().unwrap();
+        if *placed == data { return Ok(()) }
+        json::write(file, &data)?;
+        *placed = data;
+        // Replicas moved, keep the superblocks of the volumes matching the placement map,
+        // both the local ones and those in their reserved slots
+        let dsts = superblock::checksum(0, &placement.slots.concat());
+        for volume in self.described.lock().unwrap().iter() {
+            if let Some((file, superblock)) = volume.superblock.lock().unwrap().as_mut() {
+                if superblock.dsts == dsts { continue }
+                superblock.dsts = dsts;
+                superblock.save(file);
+            }
+            let volume = volume.clone();
+            runtime().spawn(async move {
+                if let Err(err) = volume.commit_superblock().await {
+                    error!("Writing superblock after the placement changed: {err:?}");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Spawn a thread saving the placement map every `interval` if it changed
+    pub fn placer(&self, interval: Duration) -> thread::JoinHandle<!> {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            sleep(interval);
+            if let Err(err) = store.save_placement() {
+                error!("Saving placement map: {err:?}");
+            }
+        })
+    }
+
+    /// Move replicas from the slowest destinations onto faster fresh ones
+    fn rebalance(&self, mut fresh: Vec<IpAddr>) {
+        let rtts = self.rtts.lock().unwrap().clone();
+        let rtt = |ip: &IpAddr| rtts.get(ip).copied().unwrap_or(Duration::MAX);
+        fresh.sort_by_key(rtt);
+        let mut slowest: Vec<(usize, usize, Duration)> = self.pings.map(|ping| ping.ips.clone())
+            .into_iter().enumerate()
+            .flat_map(|(addr, ips)| ips.into_iter().enumerate()
+                .map(move |(i, ip)| (addr, i, ip)))
+            .map(|(addr, i, ip)| (addr, i, rtt(&ip)))
+            .collect();
+        slowest.sort_by_key(|(_, _, rtt)| Reverse(*rtt));
+
+        let mut moved = 0;
+        let mut slowest = slowest.into_iter();
+        for ip in fresh {
+            let Some((addr, i, _)) = slowest.next().filter(|(_, _, slow)| rtt(&ip) < *slow) else {
+                self.spares.lock().unwrap().push(ip);
+                continue
+            };
+            match runtime().block_on(self.migrate(addr, i, ip)) {
+                Err(err) => {
+                    debug!("Unable to move replica {i} of addr 0x{addr:x} to \"{ip}\": {err}");
+                    self.spares.lock().unwrap().push(ip);
+                },
+                Ok(old) => {
+                    self.spares.lock().unwrap().insert(0, old);
+                    moved += 1;
+                },
+            }
+        }
+        info!("Rebalanced {moved} replicas onto faster destinations");
+    }
+
+    /// Copy the block at `addr` onto `ip` in place of replica `i`, returning the IP replaced
+    async fn migrate(&self, addr: usize, i: usize, ip: IpAddr) -> io::Result<IpAddr> {
+        let held = self.hold(addr).await;
+        if self.dirty.get(addr).is_some() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "block is being written"));
+        }
+        let sock = connect(ip);
+        if self.alloc.taken(addr) {
+            let good = self.read_held(addr, &held).await?;
+            seed(&sock, &good).await;
+        }
+        Ok(self.swap(addr, i, sock, ip))
+    }
+
+    /// Ping each of `ips` with random payloads `rounds` times, recording whether the
+    /// payloads came back intact so the destinations move along their lifecycle
+    pub async fn probe(&self, ips: &[IpAddr], rounds: usize) {
+        let workers = num_cpus::get() * 4;
+        let probes: Vec<_> = ips.chunks(ips.len().div_ceil(workers).max(1)).map(|chunk| {
+            let (chunk, reputation) = (chunk.to_vec(), self.reputation.clone());
+            task::spawn(async move { for ip in chunk {
+                let sock = connect(ip);
+                for _ in 0..rounds {
+                    let mut data = Buf::zeroed(SIZE);
+                    data.make_mut().fill_with(random);
+                    if let Err(err) = sock.send(&data).await {
+                        debug!("Unable to probe \"{ip}\": {err:?}");
+                        reputation.record(ip, Event::Lost);
+                        continue
+                    }
+                    match sock.recv(sock.generation()).await {
+                        Err(_) => reputation.record(ip, Event::Lost),
+                        Ok(echo) if echo == data => reputation.record(ip, Event::Agreed),
+                        Ok(_) => reputation.record(ip, Event::Diverged),
+                    }
+                }
+            }})
+        }).collect();
+        for probe in probes {
+            probe.await.ok();
+        }
+    }
+
+    /// Save the lifecycle states of the destinations into the IPStore `file` if any of them
+    /// changed, leaving the file alone if it can't be read rather than emptying it
+    pub fn save(&self, file: &str) {
+        let Some(mut ips) = IPStore::read(file) else {
+            warn!("Not saving destination states, \"{file}\" couldn't be read");
+            return
+        };
+        let mut changed = false;
+        {
+            let known = self.known.lock().unwrap();
+            for dst in ips.dsts.iter_mut() {
+                let ip = IpAddr::V4(dst.ip);
+                if !known.contains(&ip) { continue }
+                let state = self.reputation.state(&ip);
+                if dst.state != state {
+                    dst.state = state;
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            ips.save(file);
+        }
+    }
+
+    /// Spawn a thread checking `file` for changes every `interval` and ingesting them
+    pub fn ingester(&self, file: String, interval: Duration, grow: bool) -> thread::JoinHandle<!> {
+        let store = self.clone();
+        let modified = |file: &str| fs::metadata(file).and_then(|meta| meta.modified()).ok();
+        let mut last = modified(&file);
+        thread::spawn(move || loop {
+            sleep(interval);
+            let now = modified(&file);
+            if now == last { continue }
+            last = now;
+            info!("Destinations in \"{file}\" changed, ingesting them");
+            store.ingest(&file, grow);
+        })
+    }
+
+    fn replicas(&self, addr: usize) -> (Vec<Arc<Socket>>, Vec<IpAddr>) {
+        self.pings.with(addr, |ping| {
+            let copies = self.copies.min(ping.copies);
+            (ping.socks[..copies].to_vec(), ping.ips[..copies].to_vec())
+        })
+    }
+
+    /// Put `ip` in place of replica `i` of `addr`, returning the IP replaced,
+    /// the placement map records it on its next save
+    fn swap(&self, addr: usize, i: usize, sock: Arc<Socket>, ip: IpAddr) -> IpAddr {
+        self.pings.update(addr, |ping| {
+            ping.socks[i] = sock;
+            mem::replace(&mut ping.ips[i], ip)
+        })
     }
 
-    fn read(&self, addr: usize) -> io::Result<Vec<u8>> {
+    /// Swap replica `i` of `addr` for a trusted spare destination, returning the new socket and IP
+    fn replace(&self, addr: usize, i: usize) -> Option<(Arc<Socket>, IpAddr)> {
+        let ip = {
+            let mut spares = self.spares.lock().unwrap();
+            let spare = spares.iter().rposition(|ip| self.reputation.state(ip).trusted())?;
+            spares.remove(spare)
+        };
+        let sock = connect(ip);
+        let old = self.swap(addr, i, sock.clone(), ip);
+        warn!("Replaced \"{old}\" with spare \"{ip}\" for addr 0x{addr:x}");
+        Some((sock, ip))
+    }
+
+    async fn read_slot(&self, addr: usize) -> io::Result<Buf> {
         trace!("Reading addr 0x{addr:x}");
-        let mut datas: Vec<Vec<u8>> = vec![];
-        let ping = &self.pings.lock().unwrap()[addr];
+        if let Some(data) = self.dirty.get(addr) {
+            return Ok(data);
+        }
+        let held = self.hold(addr).await;
+        self.read_held(addr, &held).await
+    }
 
-        for i in 0..ping.copies {
-            let mut data: [u8; 28 + SIZE] = [0; 28 + SIZE];
-            ping.socks[i].recv(&mut data)?;
-            datas.push(data[28..(28 + SIZE)].to_vec());
+    /// Read the slot `addr` from its replicas, `_held` being the guard of `hold`
+    async fn read_held(&self, addr: usize, _held: &OwnedMutexGuard<()>) -> io::Result<Buf> {
+        let (socks, ips) = self.replicas(addr);
+        let quorum = quorum(self.read_quorum, socks.len());
+        let mut rx = listen_all(&socks);
+
+        let mut replies: Vec<(usize, u64, Buf)> = vec![];
+        let mut good = None;
+        while let Some((i, gen, res)) = rx.recv().await {
+            match res {
+                Err(err) => {
+                    debug!("No reply from \"{}\" reading addr 0x{addr:x}: {err:?}", ips[i]);
+                    self.reputation.record(ips[i], Event::Lost);
+                },
+                Ok(data) => replies.push((i, gen, data)),
+            }
+            good = vote(replies.iter().map(|(_, _, data)| data), quorum);
+            if good.is_some() { break }
         }
 
-        let first: [u8; SIZE] = datas[0].clone()[..].try_into().unwrap();
-        let mut good: [u8; SIZE] = datas[0].clone()[..].try_into().unwrap();
-        for data in datas[1..].iter() {
-            if *data != good && *data == first {
-                good = first;
+        let good = match good {
+            None => {
+                // Too few copies answering isn't their fault, and the ones that did are all
+                // that's left of the block, so they go back out as they came in
+                let most = plurality(replies.iter().map(|(_, _, data)| data));
+                for (i, gen, data) in replies {
+                    if most.as_ref().is_some_and(|most| *most != data) {
+                        self.reputation.record(ips[i], Event::Diverged);
+                    }
+                    socks[i].echo(gen, &data);
+                }
+                return Err(io::Error::other(
+                    format!("no read quorum of {quorum} for addr 0x{addr:x}")));
+            },
+            Some(good) => good,
+        };
+        for (i, gen, data) in replies {
+            if data == good {
+                self.reputation.record(ips[i], Event::Agreed);
+            } else {
+                warn!("Sus response data in ping from \"{}\"", ips[i]);
+                self.reputation.record(ips[i], Event::Diverged);
             }
+            socks[i].echo(gen, &good);
         }
-        for i in 0..datas.len() {
-            if good != *datas[i] {
-                warn!("Sus response data in ping from \"{}\"", ping.ips[i]);
+        stragglers(self.reputation.clone(), ips, socks, rx, good.clone());
+        Ok(good)
+    }
+
+    /// Check every replica of `addr`, correcting the ones diverging from the quorum and
+    /// replacing the ones that went dark with spares seeded from the surviving copies
+    pub async fn repair(&self, addr: usize) -> io::Result<Repair> {
+        let mut repair = Repair::default();
+        if self.dirty.get(addr).is_some() || !self.map.in_use(addr) {
+            return Ok(repair)
+        }
+        let _held = self.hold(addr).await;
+        let (socks, ips) = self.replicas(addr);
+        let quorum = quorum(self.read_quorum, socks.len());
+        let mut missing = vec![];
+        let mut replies = vec![];
+        let mut rx = listen_all(&socks);
+        while let Some((i, gen, res)) = rx.recv().await {
+            match res {
+                Err(_) => {
+                    self.reputation.record(ips[i], Event::Lost);
+                    missing.push(i);
+                },
+                Ok(data) => replies.push((i, gen, data)),
             }
         }
-        Ok(good.to_vec())
+        let (replies, distrusted): (Vec<_>, Vec<_>) = replies.into_iter()
+            .partition(|(i, _, _)| self.reputation.state(&ips[*i]).trusted());
+        missing.extend(distrusted.into_iter().map(|(i, _, _)| i));
+
+        let Some(good) = vote(replies.iter().map(|(_, _, data)| data), quorum) else {
+            for (i, gen, data) in replies {
+                socks[i].echo(gen, &data);
+            }
+            return Err(io::Error::other(
+                format!("not enough agreeing copies of addr 0x{addr:x} to repair it")));
+        };
+        for (i, gen, data) in replies {
+            if data == good {
+                self.reputation.record(ips[i], Event::Agreed);
+            } else {
+                warn!("Correcting diverged copy of addr 0x{addr:x} on \"{}\"", ips[i]);
+                self.reputation.record(ips[i], Event::Diverged);
+                repair.corrected += 1;
+            }
+            socks[i].echo(gen, &good);
+        }
+        for i in missing {
+            let Some((sock, _)) = self.replace(addr, i) else {
+                warn!("No spare destinations left to repair addr 0x{addr:x}");
+                break
+            };
+            seed(&sock, &good).await;
+            repair.restored += 1;
+        }
+        Ok(repair)
+    }
+
+    /// Spawn the repair loop, checking every block once per `interval`
+    pub fn repairer(&self, interval: Duration) -> thread::JoinHandle<!> {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            let start = Instant::now();
+            for addr in 0..store.pings.len() {
+                match runtime().block_on(store.repair(addr)) {
+                    Err(err) => error!("Repairing addr 0x{addr:x}: {err}"),
+                    Ok(repair) => if repair.restored > 0 {
+                        info!("Restored {} copies of addr 0x{addr:x}", repair.restored);
+                    },
+                }
+            }
+            sleep(interval.saturating_sub(start.elapsed()));
+        })
+    }
+
+    /// Spawn the scrubber, walking every block at `rate` blocks per second and
+    /// repairing them, the running tally is saved to `report` after each pass
+    pub fn scrubber(&self, rate: f64, report: String) -> thread::JoinHandle<!> {
+        let store = self.clone();
+        let pace = Duration::from_secs_f64(1.0 / rate);
+        thread::spawn(move || {
+            let mut scrub = ScrubReport::load(&report);
+            loop {
+                let mut unrecoverable = vec![];
+                let mut checked = 0;
+                let mut repaired = 0;
+                for addr in 0..store.pings.len() {
+                    let start = Instant::now();
+                    match runtime().block_on(store.repair(addr)) {
+                        Err(err) => {
+                            error!("Scrubbing addr 0x{addr:x}: {err}");
+                            unrecoverable.push(addr);
+                        },
+                        Ok(repair) => if repair.corrected + repair.restored > 0 {
+                            info!("Repaired addr 0x{addr:x}: {repair:?}");
+                            repaired += 1;
+                        },
+                    }
+                    checked += 1;
+                    sleep(pace.saturating_sub(start.elapsed()));
+                }
+                info!("Scrubbed {checked} blocks, repaired {repaired} and {} unrecoverable",
+                    unrecoverable.len());
+                scrub.pass(checked, repaired, unrecoverable);
+                scrub.save(&report);
+
+                let untrusted: Vec<IpAddr> = store.spares.lock().unwrap().iter().copied()
+                    .filter(|ip| !matches!(store.reputation.state(ip), State::Quarantined | State::Retired))
+                    .filter(|ip| !store.reputation.state(ip).trusted())
+                    .collect();
+                runtime().block_on(store.probe(&untrusted, 1));
+            }
+        })
+    }
+
+    /// Round trip of a slot, that of the replica completing its read quorum
+    fn latency(&self, slot: usize, rtts: &HashMap<IpAddr, Duration>) -> Duration {
+        let (_, ips) = self.replicas(slot);
+        let mut slot: Vec<Duration> = ips.iter()
+            .map(|ip| rtts.get(ip).copied().unwrap_or(Duration::MAX))
+            .collect();
+        slot.sort();
+        let quorum = quorum(self.read_quorum, slot.len());
+        slot.get(quorum.saturating_sub(1)).copied().unwrap_or(Duration::MAX)
     }
 
-    async fn write(&self, buf: &[u8], addr: usize, off: usize, size: usize) -> io::Result<()> {
-        let mut packets = vec![];
-        for i in addr..(size.div_ceil(SIZE)) {
-            let loc = i * SIZE;
-            packets.push(buf[loc..loc+SIZE].to_vec());
+    /// Copy the block in `from` into the free slot `to` and point the volume at it
+    async fn relocate(&self, from: usize, to: usize) -> io::Result<()> {
+        let hash = self.map.hash(from);
+        if !self.map.claim(to) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("slot 0x{to:x} is taken")));
         }
-        for (pingspan, packet) in packets.iter().enumerate() {
-            let offset = addr + pingspan;
-            trace!("Writing addr 0x{offset:x}");
-            self.ping(offset, packet)?;
+        let res = match self.read_slot(from).await {
+            Ok(data) => self.ping(to, &data, self.hold(to).await).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            self.map.unclaim(to);
+            return Err(err)
         }
+        if self.dirty.get(from).is_some() || !self.map.relocate(from, to, hash) {
+            self.map.unclaim(to);
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "block was written while moving"));
+        }
+        self.drain(from);
         Ok(())
     }
 
-    fn ping(&self, addr: usize, data: &[u8]) -> io::Result<()> {
+    /// Stop circulating the copies of the released `slot`, readers still holding one may echo
+    /// it once more, which the quarantine of the slot and draining it again on reuse cover
+    fn drain(&self, slot: usize) {
+        if slot >= self.pings.len() { return }
+        for sock in self.pings.with(slot, |ping| ping.socks.clone()) {
+            sock.drain();
+        }
+    }
+
+    /// Move up to `moves` blocks between tiers, cold blocks off fast slots onto the slowest
+    /// free ones and hot blocks onto the fastest free ones, returning how many moved
+    pub async fn tier(&self, moves: usize) -> usize {
+        let rtts = self.rtts.lock().unwrap().clone();
+        let physical = self.pings.len();
+        let free = self.alloc.free(physical);
+        let mut free: Vec<(Duration, usize)> = free.into_iter()
+            .map(|slot| (self.latency(slot, &rtts), slot))
+            .collect();
+        free.sort();
+        let hottest = self.map.hottest();
+        let (hot, mut cold): (Vec<_>, Vec<_>) = hottest.into_iter()
+            .map(|(slot, heat)| (slot, heat, self.latency(slot, &rtts)))
+            .partition(|(_, heat, _)| *heat > 0);
+        cold.sort_by_key(|(_, _, rtt)| *rtt);
+
+        let mut moved = 0;
+        let mut cold = cold.into_iter();
+        let mut hot = hot.into_iter();
+        while moved < moves {
+            let (from, rtt, to) = if let Some((from, _, rtt)) = cold.next() {
+                let Some(to) = free.pop() else { break };
+                if to.0 <= rtt {
+                    free.push(to);
+                    cold = vec![].into_iter();
+                    continue
+                }
+                (from, rtt, to)
+            } else if let Some((from, _, rtt)) = hot.next() {
+                if free.is_empty() { break }
+                let to = free.remove(0);
+                if to.0 >= rtt {
+                    free.insert(0, to);
+                    continue
+                }
+                (from, rtt, to)
+            } else { break };
+            match self.relocate(from, to.1).await {
+                Err(err) => {
+                    debug!("Unable to move slot 0x{from:x} to 0x{:x}: {err}", to.1);
+                    free.push(to);
+                    free.sort();
+                },
+                Ok(()) => {
+                    trace!("Moved slot 0x{from:x} of {rtt:?} to 0x{:x} of {:?}", to.1, to.0);
+                    free.push((rtt, from));
+                    free.sort();
+                    moved += 1;
+                },
+            }
+        }
+        moved
+    }
+
+    /// Spawn a thread moving blocks between tiers every `interval`, cooling them down after
+    pub fn tierer(&self, interval: Duration, moves: usize) -> thread::JoinHandle<!> {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            sleep(interval);
+            let moved = runtime().block_on(store.tier(moves));
+            if moved > 0 {
+                info!("Moved {moved} blocks between tiers");
+            }
+            store.map.cool();
+        })
+    }
+
+    /// Read the logical block `addr`, blocks never written are zero
+    async fn read_block(&self, addr: usize) -> io::Result<Buf> {
+        match self.map.slot(addr) {
+            None => Ok(Buf::zeroed(SIZE)),
+            Some(slot) => {
+                self.map.touch(slot);
+                let mut data = self.read_slot(slot).await?;
+                if let Some(cipher) = &self.cipher {
+                    cipher.decrypt(data.make_mut());
+                }
+                Ok(data)
+            },
+        }
+    }
+
+    /// Destinations holding the other blocks of the stripe of `addr`, new slots of a stripe
+    /// go on disjoint groups of destinations so its blocks are pinged in parallel
+    fn striped_ips(&self, addr: usize) -> HashSet<IpAddr> {
+        let start = addr - addr % self.stripe;
+        let slots: Vec<usize> = (start..start + self.stripe).filter(|other| *other != addr)
+            .filter_map(|other| self.map.slot(other)).collect();
+        slots.into_iter().filter(|slot| *slot < self.pings.len())
+            .flat_map(|slot| self.pings.with(slot, |ping| ping.ips.clone())).collect()
+    }
+
+    /// Run `f` on every logical block of `addrs`, a stripe of `stripe` of them in parallel
+    /// as they sit on disjoint groups of destinations, returning the results in block order
+    async fn striped<T, F>(
+        &self, addrs: Range<usize>, f: impl Fn(PingStore, usize) -> F
+    ) -> io::Result<Vec<T>>
+    where T: Send + 'static, F: Future<Output = io::Result<T>> + Send + 'static {
+        let addrs: Vec<usize> = addrs.collect();
+        let mut res = Vec::with_capacity(addrs.len());
+        for stripe in addrs.chunks(self.stripe) {
+            let tasks: Vec<_> = stripe.iter().map(|addr| task::spawn(f(self.clone(), *addr))).collect();
+            for task in tasks {
+                res.push(task.await??);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Take a copy-on-write snapshot named `name` once pending writes are flushed,
+    /// it's kept with the blocks of the volume in the placement map
+    pub fn snapshot(&self, name: &str) -> io::Result<()> {
+        runtime().block_on(self.flush())?;
+        self.map.snapshot(name)?;
+        info!("Took snapshot \"{name}\"");
+        self.save_placement()
+    }
+
+    /// Name, creation time and block count of every snapshot
+    pub fn snapshots(&self) -> Vec<(String, u64, usize)> {
+        self.map.snapshots().iter()
+            .map(|snapshot| (snapshot.name.clone(), snapshot.created, snapshot.blocks()))
+            .collect()
+    }
+
+    /// Roll the volume back to the snapshot `name` once pending writes are flushed
+    pub fn rollback(&self, name: &str) -> io::Result<()> {
+        runtime().block_on(self.flush())?;
+        self.map.rollback(name)?;
+        info!("Rolled back to snapshot \"{name}\"");
+        self.save_placement()
+    }
+
+    /// Bytes the destinations can hold, past the slots every volume reserves for its superblock
+    pub fn physical(&self) -> u64 {
+        let reserved = RESERVED * self.described.lock().unwrap().len();
+        (self.pings.len().saturating_sub(reserved) * SIZE) as u64
+    }
+
+    /// Physical slots holding data
+    pub fn allocated(&self) -> usize {
+        self.map.allocated()
+    }
+
+    /// Logical blocks holding data, more than `allocated` when blocks are deduplicated
+    pub fn mapped(&self) -> usize {
+        self.map.mapped()
+    }
+
+    /// Read `buf.len()` bytes at byte offset `off`
+    pub async fn read(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        let addr = off as usize / SIZE;
+        let skip = off as usize % SIZE;
+        let buflen = buf.len();
+        let count = (skip + buflen).div_ceil(SIZE);
+        let blocks = self.striped(addr..addr + count,
+            |store, addr| async move { store.read_block(addr).await }).await?;
+        let mut off = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            let start = if i == 0 { skip } else { 0 };
+            let len = (SIZE - start).min(buflen - off);
+            buf[off..off + len].copy_from_slice(&block[start..start + len]);
+            off += len;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` at byte offset `off`, the blocks it covers whole are taken straight from it
+    /// and only those it covers partially are read back and patched, with `fua` the write
+    /// quorum is waited for even when writing back
+    pub async fn write(&self, buf: Buf, off: u64, fua: bool) -> io::Result<()> {
+        let off = off as usize;
+        let end = off + buf.len();
+        let blocks = off / SIZE..end.div_ceil(SIZE);
+        let mut partial = vec![];
+        for addr in blocks.clone().take(1).chain(blocks.clone().last()) {
+            let covered = (addr * SIZE).max(off)..((addr + 1) * SIZE).min(end);
+            if covered.len() == SIZE || partial.iter().any(|(other, _)| *other == addr) { continue }
+            let mut block = self.read_block(addr).await?;
+            block.make_mut()[covered.start - addr * SIZE..covered.end - addr * SIZE]
+                .copy_from_slice(&buf[covered.start - off..covered.end - off]);
+            partial.push((addr, block));
+        }
+        self.striped(blocks, |store, addr| {
+            let chunk = match partial.iter().find(|(other, _)| *other == addr) {
+                Some((_, block)) => block.clone(),
+                None => buf.slice(addr * SIZE - off..(addr + 1) * SIZE - off),
+            };
+            async move { store.write_block(addr, chunk, fua).await }
+        }).await?;
+        match fua {
+            true => self.save_placement(),
+            false => Ok(()),
+        }
+    }
+
+    /// Wait for every cached and outstanding write to reach its write quorum
+    pub async fn flush(&self) -> io::Result<()> {
+        let dirty = self.dirty.clone();
+        task::spawn_blocking(move || dirty.wait(None)).await?;
+        match self.dirty.error() {
+            Some(err) => Err(err),
+            None => self.save_placement(),
+        }
+    }
+
+    /// Write the logical block `addr`, zero blocks give up their slot instead of being sent,
+    /// others are sent encrypted if the volume has a key
+    async fn write_block(&self, addr: usize, mut data: Buf, fua: bool) -> io::Result<()> {
+        trace!("Writing addr 0x{addr:x}");
+        if self.blocks > 0 && addr >= self.blocks {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("addr 0x{addr:x} is past the end of the device")));
+        }
+        if data.iter().all(|byte| *byte == 0) {
+            let released = self.map.release(addr);
+            if let Some(slot) = released {
+                trace!("Released slot 0x{slot:x} of zeroed addr 0x{addr:x}");
+                self.drain(slot);
+            }
+            return Ok(());
+        }
+        let hash = map::hash(&data);
+        if let Some(slot) = self.map.dedup(addr, hash) {
+            trace!("Addr 0x{addr:x} deduplicated onto slot 0x{slot:x}");
+            return Ok(());
+        }
+        let physical = self.pings.len();
+        let striped = self.striped_ips(addr);
+        let fits = |slot: usize| slot < physical
+            && self.pings.with(slot, |ping| ping.ips.iter().all(|ip| !striped.contains(ip)));
+        let Some(slot) = self.map.allocate_where(addr, hash, physical, fits) else {
+            return Err(io::Error::from_raw_os_error(28)); // ENOSPC
+        };
+        if let Some(cipher) = &self.cipher {
+            cipher.encrypt(data.make_mut());
+        }
+
+        // Held from before the write is cached, so writes of a slot go out in the order they came
+        let held = self.hold(slot).await;
+        let gen = self.dirty.mark(slot, &data);
+        if self.writeback && !fua {
+            let store = self.clone();
+            task::spawn(async move {
+                let res = store.ping(slot, &data, held).await;
+                store.settle(slot, gen, hash, &res);
+            });
+            Ok(())
+        } else {
+            let res = self.ping(slot, &data, held).await;
+            self.settle(slot, gen, hash, &res);
+            res
+        }
+    }
+
+    /// Settle the write of data hashing to `hash` into `slot`, which blocks writing the same
+    /// data may only be deduplicated onto once it reached its quorum
+    fn settle(&self, slot: usize, gen: usize, hash: Hash, res: &io::Result<()>) {
+        self.dirty.settle(slot, gen, res);
+        if res.is_ok() {
+            self.map.confirm(slot, hash);
+        }
+    }
+
+    /// Only pinger of the slot `addr` until the guard is dropped, so replies to the
+    /// echoes of one read or write aren't taken by another
+    async fn hold(&self, addr: usize) -> OwnedMutexGuard<()> {
+        let pinging = self.pings.with(addr, |ping| ping.pinging.clone());
+        pinging.lock_owned().await
+    }
+
+    /// Send `data` to every replica of `addr` and wait for the write quorum to echo it,
+    /// retransmitting to spare destinations in place of replicas that time out,
+    /// `_held` being the guard of `hold`
+    async fn ping(&self, addr: usize, data: &Buf, _held: OwnedMutexGuard<()>) -> io::Result<()> {
         trace!("Sending store ping with addr 0x{addr:x}");
-        let mut packet = ICMP_PACKET.to_vec();
-        packet.append(&mut data.to_vec());
-        let checksum = checksum(&packet);
-        packet[2] = checksum[0];
-        packet[3] = checksum[1];
-        packet[6] = 0;
-        packet[7] = 1;
-        let ping = &mut self.pings.lock().unwrap()[addr];
-        let mut res = vec![];
-        for i in 0..ping.copies {
-            res.push(ping.socks[i].send(&packet)?);
+        let (socks, mut ips) = self.replicas(addr);
+        for sock in socks.iter() {
+            sock.drain();
+            sock.send(data).await?;
         }
+
+        let quorum = quorum(self.write_quorum, socks.len());
+        let mut socks = socks;
+        let mut retries = socks.len();
+        let mut waiting = socks.len();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for (i, sock) in socks.iter().enumerate() {
+            listen(i, sock.clone(), tx.clone());
+        }
+
+        let mut confirmed = 0;
+        while confirmed < quorum && waiting > 0 {
+            let (i, gen, res) = rx.recv().await.unwrap();
+            waiting -= 1;
+            match res {
+                Ok(echoed) if echoed == *data => {
+                    self.reputation.record(ips[i], Event::Agreed);
+                    socks[i].echo(gen, data);
+                    confirmed += 1;
+                },
+                Ok(_) => {
+                    warn!("Sus echo data in ping from \"{}\"", ips[i]);
+                    self.reputation.record(ips[i], Event::Diverged);
+                    socks[i].echo(gen, data);
+                },
+                Err(err) => {
+                    debug!("No echo from \"{}\" writing addr 0x{addr:x}: {err:?}", ips[i]);
+                    self.reputation.record(ips[i], Event::Lost);
+                    if retries == 0 { continue }
+                    let Some((sock, ip)) = self.replace(addr, i) else { continue };
+                    retries -= 1;
+                    ips[i] = ip;
+                    socks[i] = sock.clone();
+                    if let Err(err) = sock.send(data).await {
+                        debug!("Unable to retransmit addr 0x{addr:x} to \"{ip}\": {err:?}");
+                        continue
+                    }
+                    listen(i, sock, tx.clone());
+                    waiting += 1;
+                },
+            }
+        }
+        drop(tx);
+        if confirmed < quorum {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("only {confirmed} of {quorum} copies of addr 0x{addr:x} echoed")));
+        }
+        stragglers(self.reputation.clone(), ips, socks, rx, data.clone());
         Ok(())
     }
 }
 
+/// Blocks read per second by `readers` concurrent readers splitting `count` blocks between
+/// them, read back from a store on loopback destinations the kernel answers, a slot per block,
+/// blocks keep a single copy so the transport isn't saturated before the store's locks are
+pub fn read_throughput(count: usize, readers: usize) -> io::Result<f64> {
+    let store = PingStore::new().copies(1);
+    store.grow((0..count * COPIES).map(|i| IpAddr::from([127, 0, (i / 254) as u8, (i % 254 + 1) as u8])).collect());
+    runtime().block_on(async {
+        for addr in 0..count {
+            let mut data = [0xb1; SIZE];
+            data[..8].copy_from_slice(&(addr as u64).to_be_bytes());
+            store.write(Buf::copy(&data), (addr * SIZE) as u64, true).await?;
+        }
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..readers).map(|reader| {
+            let store = store.clone();
+            task::spawn(async move {
+                let mut buf = [0; SIZE];
+                for addr in (reader..count).step_by(readers) {
+                    store.read(&mut buf, (addr * SIZE) as u64).await?;
+                }
+                io::Result::Ok(())
+            })
+        }).collect();
+        for task in tasks {
+            task.await??;
+        }
+        Ok(count as f64 / start.elapsed().as_secs_f64())
+    })
+}
+
+/// Block state lookups per second by `threads` threads splitting `count` blocks between them,
+/// each looking up what a read does before it goes to the network, the slot of the block, its
+/// heat and its replicas, with `global` every lookup also takes one lock as before sharding
+pub fn state_throughput(count: usize, threads: usize, global: bool) -> f64 {
+    const ROUNDS: usize = 100;
+    let store = PingStore::new().copies(1);
+    store.grow((0..count * COPIES).map(|i| IpAddr::from([127, 0, (i / 254) as u8, (i % 254 + 1) as u8])).collect());
+    for addr in 0..count {
+        store.map.allocate(addr, map::hash(&addr.to_be_bytes()), store.pings.len());
+    }
+    let lock = Mutex::new(());
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for thread in 0..threads {
+            let (store, lock) = (&store, &lock);
+            scope.spawn(move || {
+                for _ in 0..ROUNDS {
+                    for addr in (thread..count).step_by(threads) {
+                        let _global = global.then(|| lock.lock().unwrap());
+                        let slot = store.map.slot(addr).unwrap();
+                        store.map.touch(slot);
+                        std::hint::black_box(store.replicas(slot));
+                    }
+                }
+            });
+        }
+    });
+    (count * ROUNDS) as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Resolve a quorum setting against the number of copies, zero means a majority
+fn quorum(setting: usize, copies: usize) -> usize {
+    match setting {
+        0 => copies / 2 + 1,
+        quorum => quorum.min(copies),
+    }
+}
+
+/// Every distinct payload of the replies with how many of them carry it
+fn tally<'a, I: IntoIterator<Item = &'a Buf>>(replies: I) -> Vec<(&'a Buf, usize)> {
+    let mut tally: Vec<(&Buf, usize)> = vec![];
+    for data in replies {
+        match tally.iter_mut().find(|(other, _)| *other == data) {
+            Some((_, count)) => *count += 1,
+            None => tally.push((data, 1)),
+        }
+    }
+    tally
+}
+
+/// The payload at least `quorum` of the replies agree on, if any
+fn vote<'a, I: IntoIterator<Item = &'a Buf>>(replies: I, quorum: usize) -> Option<Buf> {
+    tally(replies).into_iter().find(|(_, count)| *count >= quorum).map(|(data, _)| data.clone())
+}
+
+/// The payload more of the replies carry than any other, if one does
+fn plurality<'a, I: IntoIterator<Item = &'a Buf>>(replies: I) -> Option<Buf> {
+    let tally = tally(replies);
+    let most = tally.iter().map(|(_, count)| *count).max()?;
+    match &tally.iter().filter(|(_, count)| *count == most).collect::<Vec<_>>()[..] {
+        [(data, _)] => Some((*data).clone()),
+        _ => None,
+    }
+}
+
+/// Echoes to `ip` through the shared socket
+fn connect(ip: IpAddr) -> Arc<Socket> {
+    Arc::new(Socket::new(ip))
+}
+
+/// Wait for one echo on every socket in parallel
+fn listen_all(socks: &[Arc<Socket>]) -> mpsc::UnboundedReceiver<Reply> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    for (i, sock) in socks.iter().enumerate() {
+        listen(i, sock.clone(), tx.clone());
+    }
+    rx
+}
+
+/// Wait for one echo of the data circulating now on the socket of replica `i`, whoever
+/// receives it is responsible for sending a payload back out with `Socket::echo` under
+/// the generation of the reply to keep the block in circulation
+fn listen(i: usize, sock: Arc<Socket>, tx: mpsc::UnboundedSender<Reply>) {
+    let gen = sock.generation();
+    task::spawn(async move {
+        let res = sock.recv(gen).await.map(|mut data| {
+            if data.len() != SIZE {
+                data.make_mut().resize(SIZE, 0);
+            }
+            data
+        });
+        tx.send((i, gen, res)).ok();
+    });
+}
+
+/// Send `data` to a destination not holding it yet, once the congestion windows have room
+async fn seed(sock: &Socket, data: &Buf) {
+    if let Err(err) = sock.send(data).await {
+        debug!("Unable to seed copy: {err:?}");
+    }
+}
+
+/// Keep listening for the replies that arrived after a quorum was reached, so they
+/// still count towards the reputation of their destinations and stay in circulation,
+/// unless the block was overwritten since
+fn stragglers(
+    reputation: Reputation, ips: Vec<IpAddr>, socks: Vec<Arc<Socket>>,
+    mut rx: mpsc::UnboundedReceiver<Reply>, good: Buf,
+) {
+    task::spawn(async move { while let Some((i, gen, res)) = rx.recv().await {
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(_) => reputation.record(ips[i], Event::Lost),
+            Ok(data) if data == good => {
+                reputation.record(ips[i], Event::Agreed);
+                reputation.update(ips[i], |stats| stats.late += 1);
+                socks[i].echo(gen, &data);
+            },
+            Ok(_) => {
+                warn!("Sus late response data in ping from \"{}\"", ips[i]);
+                reputation.record(ips[i], Event::Diverged);
+                socks[i].echo(gen, &good);
+            },
+        }
+    }});
+}
+
+impl Default for PingStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Blocks for PingStore {
     fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
-        let addr = (off as usize).div_floor(BYTE_COUNT);
-        let buflen = buf.len();
-        let mut res = vec![];
-        for pingspan in 0..buflen.div_ceil(SIZE) {
-            res.append(&mut self.read(addr + pingspan)?.to_vec());
-        }
-        //buf.copy_from_slice(&res[0..((addr * 8) - off as usize)]);
-        buf.copy_from_slice(&res[0..buflen]);
-        Ok(())
+        runtime().block_on(self.read(buf, off))
     }
 
-    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
-        let addr = (off as usize).div_ceil(BYTE_COUNT);
-        let buflen = buf.len();
-        get_rt("channel pusher", num_cpus::get().div_floor(3) + 1).block_on(
-            self.write(buf, addr, (addr * BYTE_COUNT) - off as usize, buflen))?;
-        Ok(())
+    fn write_at(&self, buf: Buf, off: u64) -> io::Result<()> {
+        runtime().block_on(self.write(buf, off, false))
+    }
+
+    fn write_fua(&self, buf: Buf, off: u64) -> io::Result<()> {
+        runtime().block_on(self.write(buf, off, true))
     }
 
     fn size(&self) -> io::Result<u64> {
-        Ok((self.pings.lock().unwrap().len() * 56) as u64)
+        let blocks = match self.blocks {
+            0 => self.pings.len().saturating_sub(RESERVED),
+            blocks => blocks,
+        };
+        Ok((blocks * SIZE) as u64)
     }
 
     fn flush(&self) -> io::Result<()> {
+        runtime().block_on(PingStore::flush(self))
+    }
+
+    /// Discard the blocks fully covered by `len` bytes at `off`, releasing their slots
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let (off, len) = (off as usize, len as usize);
+        let released: Vec<usize> = (off.div_ceil(SIZE)..(off + len) / SIZE)
+            .filter_map(|addr| self.map.release(addr)).collect();
+        for slot in released {
+            self.drain(slot);
+        }
+        Ok(())
+    }
+
+    /// Zero `len` bytes at `off`, whole blocks just give up their slots
+    fn write_zeroes(&self, off: u64, len: u64) -> io::Result<()> {
+        let (start, end) = (off as usize, (off + len) as usize);
+        let (first, last) = (start.div_ceil(SIZE) * SIZE, end / SIZE * SIZE);
+        if first >= last {
+            return self.write_at(Buf::zeroed(end - start), off);
+        }
+        self.trim(first as u64, (last - first) as u64)?;
+        if start < first {
+            self.write_at(Buf::zeroed(first - start), off)?;
+        }
+        if last < end {
+            self.write_at(Buf::zeroed(end - last), last as u64)?;
+        }
         Ok(())
     }
 }
@@ -150,6 +1325,7 @@ impl Ping {
             socks: vec![],
             ips: vec![],
             copies: 0,
+            pinging: Arc::new(AsyncMutex::new(())),
         }
     }
 
@@ -159,3 +1335,36 @@ impl Ping {
         self.copies += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority() {
+        assert_eq!(quorum(0, 7), 4);
+        assert_eq!(quorum(0, 1), 1);
+        assert_eq!(quorum(2, 7), 2);
+        assert_eq!(quorum(9, 3), 3);
+    }
+
+    #[test]
+    fn voting() {
+        let (good, bad) = (Buf::copy(b"good"), Buf::copy(b"bad"));
+        let replies = [bad.clone(), good.clone(), bad.clone(), good.clone(), good.clone()];
+        assert_eq!(vote(&replies, 3), Some(good.clone()));
+        assert_eq!(vote(&replies[..4], 3), None);
+        assert_eq!(vote(&replies[..1], 1), Some(bad));
+        assert_eq!(vote(&[], 1), None);
+    }
+
+    #[test]
+    fn plural() {
+        let (good, bad) = (Buf::copy(b"good"), Buf::copy(b"bad"));
+        assert_eq!(plurality(&[good.clone(), bad.clone(), good.clone()]), Some(good.clone()));
+        // Copies only too few to agree on anything leave no one to blame
+        assert_eq!(plurality(&[good.clone(), bad.clone()]), None);
+        assert_eq!(plurality(std::slice::from_ref(&bad)), Some(bad));
+        assert_eq!(plurality(&[]), None);
+    }
+}
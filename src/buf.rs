@@ -0,0 +1,193 @@
+use std::{
+    hash::{Hash, Hasher},
+    ops::{Deref, Range},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    fmt};
+
+const SHARDS: usize = 16; // Independently locked pools, every thread returns buffers to one of its own
+const POOLED: usize = 1 << 12; // Buffers each pool keeps around for reuse once nothing refers to them
+
+type Storage = Arc<Vec<u8>>;
+
+static POOL: [Mutex<Vec<Storage>>; SHARDS] = [const { Mutex::new(vec![]) }; SHARDS];
+
+/// The pool the current thread returns buffers to and takes them from first
+fn home() -> usize {
+    static THREADS: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static HOME: usize = THREADS.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+    HOME.with(|home| *home)
+}
+
+/// A reference-counted payload drawn from a pool, cloning only bumps the count and its
+/// storage goes back to the pool once the last reference is dropped, a buffer may be a part
+/// of its storage to share it with the packet or the request it was cut out of
+#[derive(Clone)]
+pub struct Buf {
+    data: Storage,
+    start: usize,
+    end: Option<usize>, // Where the buffer ends if before the end of its storage
+}
+
+impl Buf {
+    /// A pooled buffer holding a copy of `data`
+    pub fn copy(data: &[u8]) -> Self {
+        let mut buf = Self::take();
+        buf.make_mut().extend_from_slice(data);
+        buf
+    }
+
+    /// A pooled buffer of `len` zeroes
+    pub fn zeroed(len: usize) -> Self {
+        let mut buf = Self::take();
+        buf.make_mut().resize(len, 0);
+        buf
+    }
+
+    /// An empty pooled buffer with room for `len` bytes, to receive into its spare capacity
+    #[cfg_attr(feature = "uring", allow(dead_code))] // io_uring receives into registered buffers
+    pub fn with_capacity(len: usize) -> Self {
+        let mut buf = Self::take();
+        buf.make_mut().reserve(len);
+        buf
+    }
+
+    /// An empty buffer from the pool of the current thread, or of any other one that isn't
+    /// busy, or a new one if they're all dry
+    fn take() -> Self {
+        let home = home();
+        let data = (0..SHARDS).find_map(|i| match i {
+            0 => POOL[home].lock().unwrap().pop(),
+            i => POOL[(home + i) % SHARDS].try_lock().ok()?.pop(),
+        });
+        let mut buf = Self { data: data.unwrap_or_default(), start: 0, end: None };
+        buf.make_mut().clear();
+        buf
+    }
+
+    fn end(&self) -> usize {
+        self.end.unwrap_or(self.data.len())
+    }
+
+    /// The bytes past the first `len`, sharing the storage of this buffer
+    pub fn skip(&self, len: usize) -> Self {
+        self.slice(len..self.len())
+    }
+
+    /// The bytes within `range` of the buffer, sharing its storage
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        let end = (self.start + range.end).min(self.end());
+        let start = (self.start + range.start).min(end);
+        Self { data: self.data.clone(), start, end: Some(end).filter(|end| *end < self.data.len()) }
+    }
+
+    /// The bytes of the buffer to change in place, copied first if they're shared, and moved
+    /// to the beginning of the storage if the buffer is only a part of it
+    pub fn make_mut(&mut self) -> &mut Vec<u8> {
+        if Arc::get_mut(&mut self.data).is_none() {
+            *self = Self::copy(self);
+        }
+        let end = self.end();
+        let data = Arc::get_mut(&mut self.data).unwrap();
+        data.truncate(end);
+        data.drain(..self.start);
+        (self.start, self.end) = (0, None);
+        data
+    }
+}
+
+impl Deref for Buf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.end()]
+    }
+}
+
+impl PartialEq for Buf {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Buf {}
+
+impl Hash for Buf {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for Buf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Buf").field(&&**self).finish()
+    }
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.data) > 1 { return }
+        let mut pool = POOL[home()].lock().unwrap();
+        if pool.len() < POOLED {
+            pool.push(self.data.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared() {
+        let buf = Buf::copy(b"block");
+        let mut copy = buf.clone();
+        assert!(Arc::ptr_eq(&buf.data, &copy.data));
+        copy.make_mut()[0] = b'B';
+        assert_eq!((&*buf, &*copy), (&b"block"[..], &b"Block"[..]));
+        assert_eq!(&*Buf::zeroed(3), &[0; 3]);
+    }
+
+    #[test]
+    fn skipped() {
+        let packet = Buf::copy(b"headerdata");
+        let data = packet.skip(6);
+        assert!(Arc::ptr_eq(&packet.data, &data.data));
+        assert_eq!(data, Buf::copy(b"data"));
+        assert_eq!(format!("{data:?}"), format!("{:?}", Buf::copy(b"data")));
+        assert!(packet.skip(20).is_empty());
+
+        // Slices stop short of the end of the storage as well
+        let block = packet.slice(2..8);
+        assert!(Arc::ptr_eq(&packet.data, &block.data));
+        assert_eq!((&*block, &*block.skip(4), &*block.slice(1..3)), (&b"aderda"[..], &b"da"[..], &b"de"[..]));
+        assert!(packet.slice(8..20) == Buf::copy(b"ta") && packet.slice(12..20).is_empty());
+        let mut changed = block.clone();
+        changed.make_mut().push(b'!');
+        assert_eq!((&*changed, &*block), (&b"aderda!"[..], &b"aderda"[..]));
+
+        // Changing it leaves the packet alone and moves the data to the start of its own storage
+        let mut changed = data.clone();
+        changed.make_mut().push(b'!');
+        assert_eq!((&*changed, &*data, &*packet), (&b"data!"[..], &b"data"[..], &b"headerdata"[..]));
+        assert_eq!(changed.start, 0);
+    }
+
+    #[test]
+    fn pooled() {
+        // Storage dropped by its last reference is kept by a pool, and handed out again emptied
+        let buf = Buf::copy(b"pooled");
+        let storage = Arc::downgrade(&buf.data);
+        drop(buf);
+        assert!(storage.upgrade().is_some());
+        assert_eq!(&*Buf::copy(b"reused"), b"reused");
+
+        // Still referenced storage isn't
+        let buf = Buf::with_capacity(1500);
+        assert!(buf.is_empty() && buf.data.capacity() >= 1500);
+        let kept = buf.clone();
+        drop(buf);
+        assert_eq!(Arc::strong_count(&kept.data), 1);
+    }
+}
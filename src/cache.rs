@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, Condvar},
+    io};
+
+use log::error;
+
+use crate::buf::Buf;
+
+/// Blocks written to a store but not yet echoed by their write quorum
+#[derive(Default)]
+pub struct Dirty {
+    blocks: Mutex<HashMap<usize, (usize, Buf)>>, // addr -> (generation, data)
+    generation: AtomicUsize,
+    clean: Condvar,
+    error: Mutex<Option<io::Error>>,
+}
+
+impl Dirty {
+    /// Mark `addr` dirty with `data`, returning the generation of this write
+    pub fn mark(&self, addr: usize, data: &Buf) -> usize {
+        let gen = self.generation.fetch_add(1, Ordering::Relaxed);
+        self.blocks.lock().unwrap().insert(addr, (gen, data.clone()));
+        gen
+    }
+
+    /// Latest data written to `addr` if it hasn't been confirmed yet
+    pub fn get(&self, addr: usize) -> Option<Buf> {
+        self.blocks.lock().unwrap().get(&addr).map(|(_, data)| data.clone())
+    }
+
+    /// Record the outcome of writing generation `gen` of `addr`,
+    /// later writes to the same address supersede it
+    pub fn settle(&self, addr: usize, gen: usize, res: &io::Result<()>) {
+        let mut blocks = self.blocks.lock().unwrap();
+        if !matches!(blocks.get(&addr), Some((latest, _)) if *latest == gen) { return }
+        blocks.remove(&addr);
+        if let Err(err) = res {
+            error!("Write of addr 0x{addr:x} failed: {err}");
+            self.error.lock().unwrap().get_or_insert(io::Error::new(err.kind(), err.to_string()));
+        }
+        self.clean.notify_all();
+    }
+
+    /// Block until every address in `addrs` is confirmed, or all of them if `None`
+    pub fn wait(&self, addrs: Option<&[usize]>) {
+        let mut blocks = self.blocks.lock().unwrap();
+        while match addrs {
+            None => !blocks.is_empty(),
+            Some(addrs) => addrs.iter().any(|addr| blocks.contains_key(addr)),
+        } {
+            blocks = self.clean.wait(blocks).unwrap();
+        }
+    }
+
+    /// Take the first write error seen since the last call
+    pub fn error(&self) -> Option<io::Error> {
+        self.error.lock().unwrap().take()
+    }
+}
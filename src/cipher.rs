@@ -0,0 +1,96 @@
+use sha2::{Digest, Sha256};
+
+const ROUNDS: u8 = 4; // Feistel rounds, four make a strong pseudorandom permutation
+
+/// Length preserving encryption of blocks under the key of a volume, a Feistel network over
+/// the two halves of a block with SHA-256 of the key, the round and the other half as round
+/// function, so encrypted blocks still fit the echo data, equal blocks encrypt alike which
+/// deduplication tells anyway
+#[derive(Clone)]
+pub struct Cipher {
+    key: [u8; 32],
+}
+
+impl Cipher {
+    /// A cipher keyed by the hash of `secret`, of any length
+    pub fn new(secret: &[u8]) -> Self {
+        Self { key: Sha256::digest(secret).into() }
+    }
+
+    /// Fingerprint of the key, telling whether a volume is opened with the key it was written with
+    pub fn fingerprint(&self) -> u64 {
+        let digest = Sha256::new().chain_update(b"fingerprint").chain_update(self.key).finalize();
+        u64::from_le_bytes(digest[..8].try_into().unwrap())
+    }
+
+    /// XOR the round function of `round` over `from` into `into`
+    fn round(&self, round: u8, from: &[u8], into: &mut [u8]) {
+        for (i, chunk) in into.chunks_mut(32).enumerate() {
+            let pad = Sha256::new()
+                .chain_update(self.key)
+                .chain_update([round])
+                .chain_update((i as u32).to_le_bytes())
+                .chain_update(from)
+                .finalize();
+            for (byte, pad) in chunk.iter_mut().zip(pad) {
+                *byte ^= pad;
+            }
+        }
+    }
+
+    /// Encrypt the block `data` in place
+    pub fn encrypt(&self, data: &mut [u8]) {
+        let (left, right) = data.split_at_mut(data.len() / 2);
+        for round in 0..ROUNDS {
+            match round % 2 {
+                0 => self.round(round, left, right),
+                _ => self.round(round, right, left),
+            }
+        }
+    }
+
+    /// Decrypt the block `data` in place
+    pub fn decrypt(&self, data: &mut [u8]) {
+        let (left, right) = data.split_at_mut(data.len() / 2);
+        for round in (0..ROUNDS).rev() {
+            match round % 2 {
+                0 => self.round(round, left, right),
+                _ => self.round(round, right, left),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let cipher = Cipher::new(b"secret");
+        for len in [64, 65, 200] {
+            let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut data = plain.clone();
+            cipher.encrypt(&mut data);
+            assert_ne!(data, plain);
+            cipher.decrypt(&mut data);
+            assert_eq!(data, plain);
+        }
+    }
+
+    #[test]
+    fn keyed() {
+        let (one, two) = (Cipher::new(b"one"), Cipher::new(b"two"));
+        let (mut first, mut second) = ([7; 64], [7; 64]);
+        one.encrypt(&mut first);
+        two.encrypt(&mut second);
+        assert_ne!(first, second);
+        // A single changed byte changes both halves
+        let mut changed = [7; 64];
+        changed[0] = 8;
+        one.encrypt(&mut changed);
+        assert!(first[..32] != changed[..32] && first[32..] != changed[32..]);
+        assert_ne!(one.fingerprint(), two.fingerprint());
+        assert_eq!(one.fingerprint(), Cipher::new(b"one").fingerprint());
+    }
+}
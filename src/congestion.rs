@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant}};
+
+use log::debug;
+use tokio::sync::Notify;
+
+use crate::rto;
+
+const INITIAL: f64 = 8.0; // Pings a destination starts off allowed in flight
+const MIN: f64 = 1.0;
+const MAX: f64 = 256.0;
+const GLOBAL_INITIAL: f64 = 1024.0; // Pings the whole store starts off allowed in flight
+const GLOBAL_MIN: f64 = 64.0;
+const GLOBAL_MAX: f64 = 65536.0;
+const SPREAD: usize = 4; // Destinations losing echoes within a retransmission timeout before the global window is cut
+
+static CONGESTION: LazyLock<Congestion> = LazyLock::new(Default::default);
+
+/// Pings in flight and how many are allowed, doubling every window of replies until the
+/// first loss and growing by one per window of them after, halving on loss at most once
+/// per retransmission timeout
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    cwnd: f64,
+    ssthresh: f64, // Window up to which it grows by one per reply
+    min: f64,
+    max: f64,
+    outstanding: usize,
+    decreased: Option<Instant>,
+}
+
+impl Window {
+    fn new(cwnd: f64, min: f64, max: f64) -> Self {
+        Self { cwnd, ssthresh: max, min, max, outstanding: 0, decreased: None }
+    }
+
+    fn open(&self) -> bool {
+        (self.outstanding as f64) < self.cwnd.floor()
+    }
+
+    fn ack(&mut self) {
+        let grow = match self.cwnd < self.ssthresh {
+            true => 1.0,
+            false => 1.0 / self.cwnd,
+        };
+        self.cwnd = (self.cwnd + grow).min(self.max);
+    }
+
+    /// Halve the window, unless it was already halved for a loss within `hold`
+    fn loss(&mut self, hold: Duration) -> bool {
+        if self.decreased.is_some_and(|at| at.elapsed() < hold) { return false }
+        self.decreased = Some(Instant::now());
+        self.cwnd = (self.cwnd / 2.0).max(self.min);
+        self.ssthresh = self.cwnd;
+        true
+    }
+}
+
+/// The global window and one per destination, with the destinations that lost echoes lately
+struct Windows {
+    global: Window,
+    windows: HashMap<IpAddr, Window>,
+    losing: HashSet<IpAddr>,
+    losing_since: Option<Instant>,
+}
+
+impl Default for Windows {
+    fn default() -> Self {
+        Self {
+            global: Window::new(GLOBAL_INITIAL, GLOBAL_MIN, GLOBAL_MAX),
+            windows: HashMap::new(),
+            losing: HashSet::new(),
+            losing_since: None,
+        }
+    }
+}
+
+impl Windows {
+    fn window(&mut self, ip: IpAddr) -> &mut Window {
+        self.windows.entry(ip).or_insert(Window::new(INITIAL, MIN, MAX))
+    }
+
+    /// Take room for a ping to `ip` if both its window and the global one have some, or
+    /// regardless with `force`
+    fn take(&mut self, ip: IpAddr, force: bool) -> bool {
+        let open = self.global.open() && self.window(ip).open();
+        if !open && !force { return false }
+        self.global.outstanding += 1;
+        self.window(ip).outstanding += 1;
+        true
+    }
+
+    fn release(&mut self, ip: IpAddr) {
+        self.global.outstanding = self.global.outstanding.saturating_sub(1);
+        if let Some(window) = self.windows.get_mut(&ip) {
+            window.outstanding = window.outstanding.saturating_sub(1);
+        }
+    }
+
+    fn ack(&mut self, ip: IpAddr) {
+        self.global.ack();
+        self.window(ip).ack();
+    }
+
+    /// Cut the window of `ip`, and the global one too once `SPREAD` destinations lost echoes
+    /// within `hold`, as a single one losing them is more likely rate limiting us than the
+    /// way out being congested
+    fn loss(&mut self, ip: IpAddr, hold: Duration) {
+        let window = self.window(ip);
+        if window.loss(hold) {
+            debug!("Backing off \"{ip}\" to {:.1} pings in flight", window.cwnd);
+        }
+        if self.losing_since.is_none_or(|since| since.elapsed() >= hold) {
+            self.losing.clear();
+            self.losing_since = Some(Instant::now());
+        }
+        self.losing.insert(ip);
+        if self.losing.len() >= SPREAD && self.global.loss(hold) {
+            debug!("Backing off to {:.1} pings in flight after {} destinations lost echoes",
+                self.global.cwnd, self.losing.len());
+            self.losing.clear();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Congestion {
+    windows: Mutex<Windows>,
+    opened: Notify,
+}
+
+/// Wait until both the window of `ip` and the global one have room for a new ping, taking it
+pub async fn acquire(ip: IpAddr) {
+    loop {
+        let opened = CONGESTION.opened.notified();
+        if CONGESTION.windows.lock().unwrap().take(ip, false) { return }
+        opened.await;
+    }
+}
+
+/// Take room for an echo to `ip` sent back out in place of a reply without waiting for it,
+/// so new pings only get the room the echoes in flight leave
+pub fn take(ip: IpAddr) {
+    CONGESTION.windows.lock().unwrap().take(ip, true);
+}
+
+/// Give back the room a ping to `ip` took once it's answered or lost
+pub fn release(ip: IpAddr) {
+    CONGESTION.windows.lock().unwrap().release(ip);
+    CONGESTION.opened.notify_waiters();
+}
+
+/// Probe for more room after a reply from `ip`
+pub fn ack(ip: IpAddr) {
+    CONGESTION.windows.lock().unwrap().ack(ip);
+}
+
+/// Back off after an echo to `ip` was lost, it may be rate limiting us or a router on the way
+pub fn loss(ip: IpAddr) {
+    let hold = rto::timeout(ip);
+    CONGESTION.windows.lock().unwrap().loss(ip, hold);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const HOLD: Duration = Duration::from_secs(60);
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn aimd() {
+        let mut window = Window::new(INITIAL, MIN, MAX);
+        // Doubling every window of replies until the first loss
+        for _ in 0..8 {
+            window.ack();
+        }
+        assert_eq!(window.cwnd, 16.0);
+        assert!(window.loss(HOLD));
+        assert_eq!((window.cwnd, window.ssthresh), (8.0, 8.0));
+        // Only halved once per retransmission timeout
+        assert!(!window.loss(HOLD));
+        assert_eq!(window.cwnd, 8.0);
+        // Then growing by one per window of replies
+        for _ in 0..8 {
+            window.ack();
+        }
+        assert!(window.cwnd > 8.9 && window.cwnd < 9.0);
+        for _ in 0..10 {
+            window.decreased = None;
+            window.loss(HOLD);
+        }
+        assert_eq!(window.cwnd, MIN);
+    }
+
+    #[test]
+    fn room() {
+        let mut windows = Windows::default();
+        for _ in 0..INITIAL as usize {
+            assert!(windows.take(ip(1), false));
+        }
+        assert!(!windows.take(ip(1), false));
+        assert!(windows.take(ip(2), false));
+        // Echoes sent back out take room without waiting for it, leaving new pings less
+        assert!(windows.take(ip(1), true));
+        windows.release(ip(1));
+        assert!(!windows.take(ip(1), false));
+        windows.release(ip(1));
+        assert!(windows.take(ip(1), false));
+        assert_eq!(windows.global.outstanding, INITIAL as usize + 1);
+    }
+
+    #[test]
+    fn spread() {
+        let mut windows = Windows::default();
+        // A single destination losing echoes only backs itself off
+        for _ in 0..SPREAD {
+            windows.window(ip(1)).decreased = None;
+            windows.loss(ip(1), HOLD);
+        }
+        assert_eq!(windows.global.cwnd, GLOBAL_INITIAL);
+        assert_eq!(windows.window(ip(1)).cwnd, MIN);
+        // Losses spread over several of them back off the whole store
+        for last in 2..SPREAD as u8 + 1 {
+            windows.loss(ip(last), HOLD);
+        }
+        assert_eq!(windows.global.cwnd, GLOBAL_INITIAL / 2.0);
+        assert_eq!(windows.window(ip(2)).cwnd, INITIAL / 2.0);
+        assert!(windows.losing.is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    collections::BTreeMap,
+    os::unix::net::{UnixListener, UnixStream},
+    io::{self, BufRead, BufReader, Write, Read},
+    fs, thread};
+
+use log::{debug, info, error};
+use crate::nbd::Blocks;
+use crate::PingStore;
+
+/// Volumes served by a daemon, by name
+pub type Volumes = BTreeMap<String, PingStore>;
+
+/// Serve line based commands for `volumes` on the unix socket `path`
+pub fn serve(volumes: Volumes, path: &str) -> io::Result<thread::JoinHandle<()>> {
+    if let Err(err) = fs::remove_file(path) {
+        debug!("Removing old control socket \"{path}\": {err:?}");
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for commands on \"{path}\"");
+    Ok(thread::spawn(move || for stream in listener.incoming() {
+        match stream {
+            Err(err) => error!("Accepting control connection: {err:?}"),
+            Ok(stream) => if let Err(err) = handle(&volumes, stream) {
+                error!("Handling control connection: {err:?}");
+            },
+        }
+    }))
+}
+
+fn handle(volumes: &Volumes, mut stream: UnixStream) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    debug!("Control command: {}", line.trim());
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let volume = |name: &str| volumes.get(name).ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound, format!("no volume named \"{name}\"")));
+    let reply = match words[..] {
+        ["volume", "list"] => Ok(volumes.iter()
+            .map(|(name, store)| format!("{name}\t{} bytes\t{} slots", store.size().unwrap_or_default(), store.allocated()))
+            .collect::<Vec<String>>().join("\n")),
+        ["snapshot", vol, "create", name] => volume(vol)
+            .and_then(|store| store.snapshot(name))
+            .map(|_| format!("Took snapshot \"{name}\" of \"{vol}\"")),
+        ["snapshot", vol, "rollback", name] => volume(vol)
+            .and_then(|store| store.rollback(name))
+            .map(|_| format!("Rolled \"{vol}\" back to \"{name}\"")),
+        ["snapshot", vol, "list"] => volume(vol).map(|store| store.snapshots().iter()
+            .map(|(name, created, blocks)| format!("{name}\t{created}\t{blocks} blocks"))
+            .collect::<Vec<String>>().join("\n")),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown command: {}", line.trim()))),
+    };
+    match reply {
+        Ok(reply) => writeln!(stream, "{reply}"),
+        Err(err) => writeln!(stream, "error: {err}"),
+    }
+}
+
+/// Send `command` to the server listening on `path`, returning its reply
+pub fn request(path: &str, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{command}")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
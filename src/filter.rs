@@ -0,0 +1,193 @@
+use std::io;
+
+use libc::{
+    BPF_LD, BPF_LDX, BPF_ST, BPF_ALU, BPF_JMP, BPF_RET, BPF_MISC,
+    BPF_W, BPF_H, BPF_B, BPF_ABS, BPF_IND, BPF_MEM, BPF_MSH,
+    BPF_AND, BPF_LSH, BPF_ADD, BPF_JEQ, BPF_K, BPF_X, BPF_TAX,
+    sock_filter};
+use socket2::SockFilter;
+
+use crate::ICMP_PACKET;
+
+const ACCEPT: u32 = 0xffff; // Bytes of an accepted packet kept
+const REJECT: u32 = 0;
+const ECHO_REPLY: u32 = 0;
+const ECHO_REQUEST: u32 = 8;
+const UNREACHABLE: u32 = 3;
+const TIME_EXCEEDED: u32 = 11;
+const IDENT: u32 = u16::from_be_bytes([ICMP_PACKET[4], ICMP_PACKET[5]]) as u32; // Identifier of store pings
+
+const fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+const fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code: (BPF_JMP | BPF_JEQ | code) as u16, jt, jf, k }
+}
+
+fn attach(sock: &socket2::Socket, program: &[sock_filter]) -> io::Result<()> {
+    let program: Vec<SockFilter> = program.iter()
+        .map(|insn| SockFilter::new(insn.code, insn.jt, insn.jf, insn.k))
+        .collect();
+    sock.attach_filter(&program)
+}
+
+/// Only let echo replies to store pings through `sock`, and ICMP errors quoting them
+pub(crate) fn store(sock: &socket2::Socket) -> io::Result<()> {
+    attach(sock, &STORE)
+}
+
+/// Only let echo replies to scanner pings through `sock`, their identifier and sequence number
+/// holding the IP they were sent to, and ICMP errors quoting them
+pub(crate) fn scanner(sock: &socket2::Socket) -> io::Result<()> {
+    attach(sock, &SCANNER)
+}
+
+static STORE: [sock_filter; 18] = [
+    stmt(BPF_LDX | BPF_B | BPF_MSH, 0),       // 0: X = IP header length
+    stmt(BPF_LD | BPF_B | BPF_IND, 0),        // 1: ICMP type
+    jump(BPF_K, ECHO_REPLY, 0, 2),
+    stmt(BPF_LD | BPF_H | BPF_IND, 4),        // 3: Identifier
+    jump(BPF_K, IDENT, 11, 12),
+    jump(BPF_K, UNREACHABLE, 1, 0),           // 5
+    jump(BPF_K, TIME_EXCEEDED, 0, 10),
+    stmt(BPF_LD | BPF_B | BPF_IND, 8),        // 7: X += quoted IP header length
+    stmt(BPF_ALU | BPF_AND | BPF_K, 0x0f),
+    stmt(BPF_ALU | BPF_LSH | BPF_K, 2),
+    stmt(BPF_ALU | BPF_ADD | BPF_X, 0),
+    stmt(BPF_MISC | BPF_TAX, 0),
+    stmt(BPF_LD | BPF_B | BPF_IND, 8),        // 12: Quoted ICMP type
+    jump(BPF_K, ECHO_REQUEST, 0, 3),
+    stmt(BPF_LD | BPF_H | BPF_IND, 12),       // 14: Quoted identifier
+    jump(BPF_K, IDENT, 0, 1),
+    stmt(BPF_RET | BPF_K, ACCEPT),            // 16
+    stmt(BPF_RET | BPF_K, REJECT),            // 17
+];
+
+static SCANNER: [sock_filter; 24] = [
+    stmt(BPF_LDX | BPF_B | BPF_MSH, 0),       // 0: X = IP header length
+    stmt(BPF_LD | BPF_B | BPF_IND, 0),        // 1: ICMP type
+    jump(BPF_K, ECHO_REPLY, 0, 5),
+    stmt(BPF_LD | BPF_W | BPF_ABS, 12),       // 3: Source IP
+    stmt(BPF_ST, 0),
+    stmt(BPF_LD | BPF_W | BPF_IND, 4),        // 5: Identifier and sequence number
+    stmt(BPF_LDX | BPF_W | BPF_MEM, 0),
+    jump(BPF_X, 0, 14, 15),
+    jump(BPF_K, UNREACHABLE, 1, 0),           // 8
+    jump(BPF_K, TIME_EXCEEDED, 0, 13),
+    stmt(BPF_LD | BPF_W | BPF_IND, 24),       // 10: Quoted destination IP
+    stmt(BPF_ST, 0),
+    stmt(BPF_LD | BPF_B | BPF_IND, 8),        // 12: X += quoted IP header length
+    stmt(BPF_ALU | BPF_AND | BPF_K, 0x0f),
+    stmt(BPF_ALU | BPF_LSH | BPF_K, 2),
+    stmt(BPF_ALU | BPF_ADD | BPF_X, 0),
+    stmt(BPF_MISC | BPF_TAX, 0),
+    stmt(BPF_LD | BPF_B | BPF_IND, 8),        // 17: Quoted ICMP type
+    jump(BPF_K, ECHO_REQUEST, 0, 4),
+    stmt(BPF_LD | BPF_W | BPF_IND, 12),       // 19: Quoted identifier and sequence number
+    stmt(BPF_LDX | BPF_W | BPF_MEM, 0),
+    jump(BPF_X, 0, 0, 1),
+    stmt(BPF_RET | BPF_K, ACCEPT),            // 22
+    stmt(BPF_RET | BPF_K, REJECT),            // 23
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes of `packet` the classic BPF `program` accepts, as the kernel runs it
+    fn run(program: &[sock_filter], packet: &[u8]) -> u32 {
+        let load = |at: u32, size: u32| -> Option<u32> {
+            let bytes = packet.get(at as usize..(at + size) as usize)?;
+            Some(bytes.iter().fold(0, |word, byte| word << 8 | *byte as u32))
+        };
+        let (mut a, mut x, mut mem, mut pc) = (0u32, 0u32, [0u32; 16], 0);
+        loop {
+            let insn = program[pc];
+            let (code, k) = (insn.code as u32, insn.k);
+            let size = match code & 0x18 { BPF_W => 4, BPF_H => 2, _ => 1 };
+            pc += 1;
+            match code & 0x07 {
+                BPF_LD => a = match code & 0xe0 {
+                    BPF_ABS => match load(k, size) { Some(a) => a, None => return REJECT },
+                    BPF_IND => match load(x + k, size) { Some(a) => a, None => return REJECT },
+                    BPF_MEM => mem[k as usize],
+                    _ => k,
+                },
+                BPF_LDX => x = match code & 0xe0 {
+                    BPF_MSH => match load(k, 1) { Some(b) => (b & 0x0f) * 4, None => return REJECT },
+                    BPF_MEM => mem[k as usize],
+                    _ => k,
+                },
+                BPF_ST => mem[k as usize] = a,
+                BPF_ALU => {
+                    let operand = if code & BPF_X != 0 { x } else { k };
+                    a = match code & 0xf0 {
+                        BPF_AND => a & operand,
+                        BPF_LSH => a << operand,
+                        BPF_ADD => a.wrapping_add(operand),
+                        op => panic!("Unexpected ALU operation {op:#x}"),
+                    };
+                },
+                BPF_JMP => {
+                    let operand = if code & BPF_X != 0 { x } else { k };
+                    pc += if a == operand { insn.jt } else { insn.jf } as usize;
+                },
+                BPF_RET => return k,
+                BPF_MISC => x = a,
+                class => panic!("Unexpected instruction class {class:#x}"),
+            }
+        }
+    }
+
+    /// An IPv4 packet from `src` to `dst` carrying the ICMP message of `kind` with `rest` of its header and `data`
+    fn icmp(src: [u8; 4], dst: [u8; 4], kind: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0];
+        packet.extend(src);
+        packet.extend(dst);
+        packet.extend([kind, 0, 0, 0]);
+        packet.extend(rest);
+        packet.extend(data);
+        packet
+    }
+
+    const HOST: [u8; 4] = [192, 0, 2, 1];
+    const DST: [u8; 4] = [198, 51, 100, 7];
+    const ROUTER: [u8; 4] = [203, 0, 113, 9];
+    const STORE_PING: [u8; 4] = [ICMP_PACKET[4], ICMP_PACKET[5], 0, 42];
+
+    #[test]
+    fn store() {
+        let reply = icmp(DST, HOST, ECHO_REPLY as u8, STORE_PING, &[0x66; 8]);
+        assert_eq!(run(&STORE, &reply), ACCEPT);
+        let other = icmp(DST, HOST, ECHO_REPLY as u8, [0x12, 0x34, 0, 42], &[0x66; 8]);
+        assert_eq!(run(&STORE, &other), REJECT);
+        let request = icmp(DST, HOST, ECHO_REQUEST as u8, STORE_PING, &[0x66; 8]);
+        assert_eq!(run(&STORE, &request), REJECT);
+
+        // Errors only get through quoting a store ping
+        let quoted = icmp(HOST, DST, ECHO_REQUEST as u8, STORE_PING, &[]);
+        for kind in [UNREACHABLE, TIME_EXCEEDED] {
+            assert_eq!(run(&STORE, &icmp(ROUTER, HOST, kind as u8, [0; 4], &quoted)), ACCEPT);
+        }
+        let quoted = icmp(HOST, DST, ECHO_REQUEST as u8, [0x12, 0x34, 0, 42], &[]);
+        assert_eq!(run(&STORE, &icmp(ROUTER, HOST, UNREACHABLE as u8, [0; 4], &quoted)), REJECT);
+        assert_eq!(run(&STORE, &reply[..22]), REJECT);
+    }
+
+    #[test]
+    fn scanner() {
+        let reply = icmp(DST, HOST, ECHO_REPLY as u8, DST, &[0x66; 8]);
+        assert_eq!(run(&SCANNER, &reply), ACCEPT);
+        let store = icmp(DST, HOST, ECHO_REPLY as u8, STORE_PING, &[0x66; 8]);
+        assert_eq!(run(&SCANNER, &store), REJECT);
+
+        // Errors only get through quoting a probe, carrying the IP it was sent to
+        let quoted = icmp(HOST, DST, ECHO_REQUEST as u8, DST, &[]);
+        for kind in [UNREACHABLE, TIME_EXCEEDED] {
+            assert_eq!(run(&SCANNER, &icmp(ROUTER, HOST, kind as u8, [0; 4], &quoted)), ACCEPT);
+        }
+        let quoted = icmp(HOST, DST, ECHO_REQUEST as u8, STORE_PING, &[]);
+        assert_eq!(run(&SCANNER, &icmp(ROUTER, HOST, UNREACHABLE as u8, [0; 4], &quoted)), REJECT);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write, Read},
+    process,
+    sync::atomic::{AtomicUsize, Ordering}};
+
+use log::{trace, debug, error};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Save `value` as JSON into `file_name`, see `write`
+pub fn save<T: Serialize>(file_name: &str, value: &T) -> io::Result<()> {
+    write(file_name, &serde_json::to_vec(value)?)
+}
+
+/// Write `data` into a temporary file unique to this call and rename it over `file_name`,
+/// so neither a crash nor saves racing each other leave a half written file behind
+pub fn write(file_name: &str, data: &[u8]) -> io::Result<()> {
+    static SAVES: AtomicUsize = AtomicUsize::new(0);
+    let tmp = format!("{file_name}.{}.{}.tmp", process::id(), SAVES.fetch_add(1, Ordering::Relaxed));
+    let mut file = OpenOptions::new()
+        .write(true).create(true).truncate(true)
+        .open(&tmp)?;
+    let res = file.write_all(data).and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp, file_name));
+    if res.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    res
+}
+
+/// Load the `what` saved as JSON in `file_name`, none if there's no such file or it's unreadable
+pub fn load<T: DeserializeOwned>(file_name: &str, what: &str) -> Option<T> {
+    let mut input = String::new();
+    match OpenOptions::new().read(true).open(file_name) {
+        Err(err) => {
+            debug!("Opening {what} \"{file_name}\": {err:?}");
+            return None;
+        },
+        Ok(mut file) => match file.read_to_string(&mut input) {
+            Err(err) => {
+                error!("Reading {what} \"{file_name}\": {err:?}");
+                return None;
+            },
+            Ok(len) => trace!("Read {len} bytes from {what}"),
+        }
+    }
+    match serde_json::from_str(&input) {
+        Err(err) => {
+            error!("Loading {what}: {err:?}");
+            None
+        },
+        Ok(value) => Some(value),
+    }
+}
@@ -1,17 +1,40 @@
-#![feature(bigint_helper_methods)]
 //#![feature(async_closure)]
-#![feature(int_roundings)]
 #![feature(never_type)]
 #![feature(ip)]
 
 pub mod scanner;
 //pub mod pinger;
 pub mod blocks;
+pub mod stats;
+pub mod scrub;
+pub mod lifecycle;
+pub mod map;
+pub mod control;
+pub mod superblock;
+pub mod placement;
+pub mod nbd;
+mod buf;
+mod cache;
+mod cipher;
+mod congestion;
+mod filter;
+mod json;
+mod rto;
+mod slots;
+mod socket;
 mod store;
+#[cfg(feature = "uring")]
+mod uring;
 pub use blocks::PingStore;
 pub use scanner::Scanner;
 //pub use pinger::Pinger;
+pub use stats::Reputation;
+pub use scrub::ScrubReport;
+pub use superblock::Superblock;
 pub use store::IPStore;
+pub use socket::throughput;
+pub use blocks::{read_throughput, state_throughput};
+pub use buf::Buf;
 
 /// ICMP packet header template
 pub const ICMP_PACKET: [u8; 8] = [
@@ -28,10 +51,13 @@ pub const RESPONSE_SIZE: usize = PACKET_SIZE + 12;
 pub const BYTE_COUNT: usize = SIZE / 8;
 
 pub static mut TIMEOUT: Option<std::time::Duration> = None;
+/// Packets sent or received per syscall, one sends and receives them one at a time
+pub static mut BATCH: usize = 32;
 
-/// Get an IcmpSocket from IpAddr and set the global timeout on the socket
-pub fn connect(ip: std::net::IpAddr) -> icmp::IcmpSocket {
-    let sock = match icmp::IcmpSocket::connect(ip) {
+/// Get a raw ICMP socket connected to IpAddr and set the global timeout on the socket
+pub fn connect(ip: std::net::IpAddr) -> socket2::Socket {
+    let sock = match socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::RAW, Some(socket2::Protocol::ICMPV4))
+        .and_then(|sock| sock.connect(&std::net::SocketAddr::new(ip, 0).into()).map(|_| sock)) {
         Err(err) => panic!("Unable to open ICMP socket: {err:?}"),
         Ok(sock) => sock,
     };
@@ -64,17 +90,22 @@ pub fn rand_ip() -> std::net::Ipv4Addr {
 }
 
 #[inline]
-/// Calculate the "internet checksum"
+/// Calculate the "internet checksum" (RFC 1071)
 pub fn checksum(bytes: &[u8]) -> [u8; 2] {
-    let mut calc = internet_checksum::Checksum::new();
-    calc.add_bytes(bytes);
-    calc.checksum()
+    let mut sum: u32 = bytes.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    (!(sum as u16)).to_be_bytes()
 }
 
 #[inline]
 /// Get a new tokio multi thread runtime
 pub fn get_rt(name: &str, threads: usize) -> tokio::runtime::Runtime {
     match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
         .worker_threads(threads)
         .thread_name(name)
         .build() {
@@ -82,3 +113,9 @@ pub fn get_rt(name: &str, threads: usize) -> tokio::runtime::Runtime {
             Ok(rt) => rt,
         }
 }
+
+/// The long-lived runtime driving every store socket, built on first use
+pub fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| get_rt("blues store", num_cpus::get()))
+}
@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+/// Agreeing echoes a destination must return before it's trusted with data
+pub const PROBATION: usize = 8;
+/// Lost or garbled copies a degraded destination may have before it's quarantined
+pub const STRIKES: usize = 3;
+
+/// Where a destination is in its life, only active and degraded ones hold data
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum State {
+    /// Found but not yet checked
+    #[default]
+    Candidate,
+    /// Answering, now proving it echoes data faithfully
+    Probation { passed: usize },
+    /// Trusted with data
+    Active,
+    /// Trusted, but recently lost or garbled a copy
+    Degraded { strikes: usize },
+    /// Not trusted with data until it passes a scan again
+    Quarantined,
+    /// Gone for good
+    Retired,
+}
+
+/// Something observed about a destination
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    /// Scanned, with whether the reply was intact
+    Scanned(bool),
+    /// Echoed the payload the quorum agreed on
+    Agreed,
+    /// Echoed a payload differing from the quorum
+    Diverged,
+    /// Didn't answer in time
+    Lost,
+}
+
+impl State {
+    /// The state after `event`
+    pub fn next(self, event: Event) -> Self {
+        use {State::*, Event::*};
+        match (self, event) {
+            (Retired, _) => Retired,
+            (Candidate | Quarantined, Scanned(true)) => Probation { passed: 0 },
+            (Candidate | Quarantined, Scanned(false)) => Retired,
+            (Active, Scanned(false)) => Degraded { strikes: 1 },
+            (state, Scanned(_)) => state,
+
+            (Candidate, Agreed) => Probation { passed: 1 },
+            (Candidate, Diverged) => Quarantined,
+            (Candidate, Lost) => Candidate,
+
+            (Probation { passed }, Agreed) if passed + 1 >= PROBATION => Active,
+            (Probation { passed }, Agreed) => Probation { passed: passed + 1 },
+            (Probation { .. }, Diverged) => Quarantined,
+            (Probation { .. }, Lost) => Probation { passed: 0 },
+
+            (Active, Agreed) => Active,
+            (Active, Diverged | Lost) => Degraded { strikes: 1 },
+
+            (Degraded { strikes }, Agreed) if strikes <= 1 => Active,
+            (Degraded { strikes }, Agreed) => Degraded { strikes: strikes - 1 },
+            (Degraded { strikes }, Diverged | Lost) if strikes + 1 >= STRIKES => Quarantined,
+            (Degraded { strikes }, Diverged | Lost) => Degraded { strikes: strikes + 1 },
+
+            (Quarantined, _) => Quarantined,
+        }
+    }
+
+    /// Whether data may be placed on a destination in this state
+    pub fn trusted(&self) -> bool {
+        matches!(self, State::Active | State::Degraded { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{State::*, Event::*, *};
+
+    fn after(state: State, events: &[Event]) -> State {
+        events.iter().fold(state, |state, event| state.next(*event))
+    }
+
+    #[test]
+    fn probation() {
+        assert_eq!(Candidate.next(Scanned(true)), Probation { passed: 0 });
+        assert_eq!(Candidate.next(Scanned(false)), Retired);
+        assert_eq!(after(Probation { passed: 0 }, &[Agreed; PROBATION]), Active);
+        assert_eq!(after(Probation { passed: 0 }, &[Agreed, Agreed, Lost]), Probation { passed: 0 });
+        assert_eq!(after(Probation { passed: 3 }, &[Diverged]), Quarantined);
+        assert!(!Probation { passed: PROBATION - 1 }.trusted());
+    }
+
+    #[test]
+    fn strikes() {
+        assert_eq!(Active.next(Lost), Degraded { strikes: 1 });
+        assert_eq!(after(Active, &[Lost, Agreed]), Active);
+        assert_eq!(after(Active, &[Diverged; STRIKES]), Quarantined);
+        assert_eq!(after(Active, &[Lost, Lost, Agreed]), Degraded { strikes: 1 });
+        assert!(Degraded { strikes: 2 }.trusted());
+        assert!(!Quarantined.trusted());
+        assert_eq!(after(Quarantined, &[Agreed, Scanned(true)]), Probation { passed: 0 });
+        assert_eq!(after(Retired, &[Scanned(true), Agreed]), Retired);
+    }
+}
@@ -0,0 +1,769 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    cmp::Reverse,
+    sync::{atomic::{AtomicUsize, Ordering}, Mutex, MutexGuard, RwLock, Arc},
+    time::{Duration, SystemTime},
+    io};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use crate::TIMEOUT;
+
+const QUARANTINE: Duration = Duration::from_secs(120); // Released slots held back when there's no global timeout
+const SHARDS: usize = 64; // Independently locked shards, consecutive slots and blocks land in different ones
+
+/// Content hash blocks are deduplicated by, collision resistant so equal hashes mean equal data
+pub type Hash = [u8; 32];
+
+/// Content hash of the block `data`
+pub fn hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// The slots of the pool one shard of the allocator hands out, those equal to its index modulo `SHARDS`
+#[derive(Default, Clone, Debug)]
+struct Shard {
+    free: Vec<usize>,
+    next: usize, // Lowest slot of the shard never handed out
+    owners: BTreeMap<usize, String>,
+    quarantine: VecDeque<(SystemTime, usize)>,
+}
+
+impl Shard {
+    /// A free slot below `physical` that `fits` accepts, recently released ones first
+    fn take(&mut self, physical: usize, fits: &impl Fn(usize) -> bool) -> Option<usize> {
+        if let Some(free) = self.free.iter().rposition(|slot| fits(*slot)) {
+            return Some(self.free.remove(free))
+        }
+        let slot = (self.next..physical).step_by(SHARDS).find(|slot| fits(*slot))?;
+        self.free.extend((self.next..slot).step_by(SHARDS));
+        self.next = slot + SHARDS;
+        Some(slot)
+    }
+}
+
+/// Hands out the physical slots of a pool, shared by every volume carved out of it, sharded
+/// so volumes allocating at once rarely wait on each other
+pub struct Allocator {
+    shards: Vec<Mutex<Shard>>,
+    cursor: AtomicUsize, // Shard the next allocation starts looking in
+}
+
+/// The allocator as saved, with every shard merged
+#[derive(Deserialize, Serialize, Default)]
+struct Saved {
+    free: Vec<usize>,
+    next: usize,
+    /// Volume every slot handed out belongs to
+    #[serde(default)]
+    owners: BTreeMap<usize, String>,
+    /// Released slots with when they were released, echoes of their old data may still be
+    /// in flight so they aren't handed out again until those timed out
+    #[serde(default)]
+    quarantine: VecDeque<(SystemTime, usize)>,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::from(Saved::default())
+    }
+}
+
+impl From<Saved> for Allocator {
+    fn from(saved: Saved) -> Self {
+        // Every shard picks up from the first of its slots at or past the saved `next`
+        let base = saved.next - saved.next % SHARDS;
+        let mut shards: Vec<Shard> = (0..SHARDS)
+            .map(|shard| match base + shard {
+                next if next < saved.next => Shard { next: next + SHARDS, ..Shard::default() },
+                next => Shard { next, ..Shard::default() },
+            })
+            .collect();
+        for slot in saved.free {
+            shards[slot % SHARDS].free.push(slot);
+        }
+        for (slot, owner) in saved.owners {
+            shards[slot % SHARDS].owners.insert(slot, owner);
+        }
+        for (released, slot) in saved.quarantine {
+            shards[slot % SHARDS].quarantine.push_back((released, slot));
+        }
+        Self { shards: shards.into_iter().map(Mutex::new).collect(), cursor: AtomicUsize::new(0) }
+    }
+}
+
+impl From<&Allocator> for Saved {
+    fn from(alloc: &Allocator) -> Self {
+        let shards: Vec<Shard> = alloc.shards.iter().map(|shard| shard.lock().unwrap().clone()).collect();
+        let next = shards.iter().map(|shard| shard.next.saturating_sub(SHARDS - 1)).max().unwrap_or_default();
+        let mut saved = Saved { next, ..Saved::default() };
+        for shard in shards {
+            saved.free.extend(shard.free.iter().copied().chain((shard.next..next).step_by(SHARDS)));
+            saved.owners.extend(shard.owners);
+            saved.quarantine.extend(shard.quarantine);
+        }
+        saved.quarantine.make_contiguous().sort();
+        saved
+    }
+}
+
+impl Serialize for Allocator {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Saved::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Allocator {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Saved::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Clone for Allocator {
+    fn clone(&self) -> Self {
+        Self::from(Saved::from(self))
+    }
+}
+
+impl std::fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Allocator").field("shards", &self.shards).finish()
+    }
+}
+
+impl Allocator {
+    fn shard(&self, slot: usize) -> MutexGuard<'_, Shard> {
+        self.shards[slot % SHARDS].lock().unwrap()
+    }
+
+    /// Take the state of `other`, as loaded from a save
+    pub fn replace(&self, other: Allocator) {
+        for (shard, other) in self.shards.iter().zip(other.shards) {
+            *shard.lock().unwrap() = other.into_inner().unwrap();
+        }
+    }
+
+    /// A free slot out of the `physical` slots of the pool for the volume `owner`,
+    /// one `fits` accepts if there is any
+    fn take(&self, physical: usize, owner: &str, fits: impl Fn(usize) -> bool) -> Option<usize> {
+        let hold = unsafe { TIMEOUT }.map_or(QUARANTINE, |timeout| timeout * 2);
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        for any in [false, true] {
+            for i in 0..SHARDS {
+                let mut shard = self.shards[(start + i) % SHARDS].lock().unwrap();
+                while let Some((released, slot)) = shard.quarantine.front().copied() {
+                    if released.elapsed().is_ok_and(|elapsed| elapsed < hold) { break }
+                    shard.quarantine.pop_front();
+                    shard.free.push(slot);
+                }
+                if let Some(slot) = shard.take(physical, &|slot| any || fits(slot)) {
+                    shard.owners.insert(slot, owner.to_string());
+                    return Some(slot)
+                }
+            }
+        }
+        None
+    }
+
+    /// Release `slot`, quarantining it as copies of its data may still be in flight
+    fn give(&self, slot: usize) {
+        let mut shard = self.shard(slot);
+        shard.owners.remove(&slot);
+        shard.quarantine.push_back((SystemTime::now(), slot));
+    }
+
+    /// Hand out the specific `slot` to `owner` if it's free, returning whether it was
+    fn claim(&self, slot: usize, owner: &str) -> bool {
+        let mut shard = self.shard(slot);
+        if let Some(free) = shard.free.iter().position(|free| *free == slot) {
+            shard.free.swap_remove(free);
+        } else if slot < shard.next {
+            return false
+        } else {
+            let next = shard.next;
+            shard.free.extend((next..slot).step_by(SHARDS));
+            shard.next = slot + SHARDS;
+        }
+        shard.owners.insert(slot, owner.to_string());
+        true
+    }
+
+    /// Give back the slots of `owner` not in `keep`, returning how many
+    pub fn disown(&self, owner: &str, keep: &HashSet<usize>) -> usize {
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let orphans: Vec<usize> = shard.owners.iter()
+                .filter(|(slot, name)| *name == owner && !keep.contains(slot))
+                .map(|(slot, _)| *slot)
+                .collect();
+            // Nothing was sent to them since the restart, so they're free at once
+            for slot in orphans.iter() {
+                shard.owners.remove(slot);
+                shard.free.push(*slot);
+            }
+            count += orphans.len();
+        }
+        count
+    }
+
+    /// Slots of the `physical` slots of the pool not handed out
+    pub fn free(&self, physical: usize) -> Vec<usize> {
+        let mut free: Vec<usize> = self.shards.iter().flat_map(|shard| {
+            let shard = shard.lock().unwrap();
+            shard.free.iter().copied().chain((shard.next..physical).step_by(SHARDS)).collect::<Vec<usize>>()
+        }).collect();
+        free.sort();
+        free
+    }
+
+    /// Whether `slot` is handed out to any volume
+    pub fn taken(&self, slot: usize) -> bool {
+        let shard = self.shard(slot);
+        slot < shard.next && !shard.free.contains(&slot)
+    }
+}
+
+/// A frozen copy of the logical to physical mapping
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    /// Seconds since the epoch when it was taken
+    pub created: u64,
+    slots: HashMap<usize, usize>,
+}
+
+impl Snapshot {
+    /// Logical blocks holding data when it was taken
+    pub fn blocks(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// What the map knows of the physical slots, every change to the map is made holding it
+#[derive(Default, Clone)]
+struct Physical {
+    refs: HashMap<usize, usize>, // physical -> logical blocks referencing it
+    index: HashMap<Hash, usize>, // content hash -> physical
+    hashes: HashMap<usize, Hash>, // physical -> content hash
+    reserved: Vec<usize>,
+    snapshots: Vec<Snapshot>,
+}
+
+/// The map as saved, with every shard merged
+#[derive(Deserialize, Serialize, Default)]
+struct SavedMap {
+    slots: HashMap<usize, usize>,
+    refs: HashMap<usize, usize>,
+    hashes: HashMap<usize, Hash>,
+    reserved: Vec<usize>,
+    snapshots: Vec<Snapshot>,
+}
+
+/// Mapping of logical block addresses onto the physical slots holding them, logical
+/// blocks without a slot are all zeroes and never sent anywhere. Slots are content
+/// addressed so logical blocks with the same data share a single circulating slot.
+/// The mapping is sharded by address so reads of blocks only wait on writes of their shard
+pub struct BlockMap {
+    slots: Vec<RwLock<HashMap<usize, usize>>>, // logical -> physical, by logical modulo `SHARDS`
+    physical: Mutex<Physical>,
+    heat: Vec<Mutex<HashMap<usize, u64>>>, // physical -> reads, halved every `cool`, by physical modulo `SHARDS`
+    alloc: Arc<Allocator>,
+    owner: String, // Volume the slots are allocated for
+}
+
+impl Default for BlockMap {
+    fn default() -> Self {
+        Self::from(SavedMap::default())
+    }
+}
+
+impl From<SavedMap> for BlockMap {
+    fn from(saved: SavedMap) -> Self {
+        let mut slots: Vec<HashMap<usize, usize>> = vec![HashMap::new(); SHARDS];
+        for (addr, slot) in saved.slots {
+            slots[addr % SHARDS].insert(addr, slot);
+        }
+        let SavedMap { refs, hashes, reserved, snapshots, .. } = saved;
+        Self {
+            slots: slots.into_iter().map(RwLock::new).collect(),
+            physical: Mutex::new(Physical { refs, hashes, reserved, snapshots, ..Physical::default() }),
+            heat: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            alloc: Arc::default(),
+            owner: String::new(),
+        }
+    }
+}
+
+impl Serialize for BlockMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.saved(&self.physical.lock().unwrap()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SavedMap::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Clone for BlockMap {
+    fn clone(&self) -> Self {
+        self.copy(&self.physical.lock().unwrap())
+    }
+}
+
+/// Copies of `maps` and of the allocator they share taken at once, so no slot the copies of
+/// the maps refer to is free in that of the allocator
+pub fn frozen(maps: &[&BlockMap], alloc: &Allocator) -> (Vec<BlockMap>, Allocator) {
+    let held: Vec<MutexGuard<Physical>> = maps.iter().map(|map| map.physical.lock().unwrap()).collect();
+    let copies = maps.iter().zip(held.iter()).map(|(map, physical)| map.copy(physical)).collect();
+    (copies, alloc.clone())
+}
+
+impl BlockMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A map of the volume `owner` allocating its slots from the shared `alloc`
+    pub fn with_allocator(alloc: Arc<Allocator>, owner: &str) -> Self {
+        Self { alloc, owner: owner.to_string(), ..Self::default() }
+    }
+
+    /// The saved map of the volume `owner` allocating its slots from the shared `alloc` again
+    pub fn restore(mut self, alloc: Arc<Allocator>, owner: &str) -> Self {
+        let physical = self.physical.get_mut().unwrap();
+        physical.index = physical.hashes.iter().map(|(slot, hash)| (*hash, *slot)).collect();
+        Self { alloc, owner: owner.to_string(), ..self }
+    }
+
+    fn saved(&self, physical: &Physical) -> SavedMap {
+        SavedMap {
+            slots: self.mapping(),
+            refs: physical.refs.clone(),
+            hashes: physical.hashes.clone(),
+            reserved: physical.reserved.clone(),
+            snapshots: physical.snapshots.clone(),
+        }
+    }
+
+    /// A copy of the map, `physical` being its held physical state
+    fn copy(&self, physical: &Physical) -> Self {
+        Self::from(self.saved(physical)).restore(self.alloc.clone(), &self.owner)
+    }
+
+    fn shard(&self, addr: usize) -> &RwLock<HashMap<usize, usize>> {
+        &self.slots[addr % SHARDS]
+    }
+
+    /// Give back the slots `alloc` has on record for this volume that the map doesn't refer to
+    pub fn reclaim(&self) -> usize {
+        let physical = self.physical.lock().unwrap();
+        let keep = physical.refs.keys().copied().collect();
+        self.alloc.disown(&self.owner, &keep)
+    }
+
+    /// Set `count` of the `physical` slots aside for the volume itself, returning them
+    pub fn reserve(&self, count: usize, physical: usize) -> Option<Vec<usize>> {
+        let mut held = self.physical.lock().unwrap();
+        while held.reserved.len() < count {
+            let slot = self.alloc.take(physical, &self.owner, |_| true)?;
+            held.refs.insert(slot, 1);
+            held.reserved.push(slot);
+        }
+        Some(held.reserved.clone())
+    }
+
+    /// Slots set aside with `reserve`
+    pub fn reserved(&self) -> Vec<usize> {
+        self.physical.lock().unwrap().reserved.clone()
+    }
+
+    /// Whether the logical block `addr` is all zeroes
+    pub fn zero(&self, addr: usize) -> bool {
+        !self.shard(addr).read().unwrap().contains_key(&addr)
+    }
+
+    /// Physical slot holding the logical block `addr`
+    pub fn slot(&self, addr: usize) -> Option<usize> {
+        self.shard(addr).read().unwrap().get(&addr).copied()
+    }
+
+    /// Whether the physical slot `slot` holds a block
+    pub fn in_use(&self, slot: usize) -> bool {
+        self.physical.lock().unwrap().refs.contains_key(&slot)
+    }
+
+    /// Point the logical block `addr` at the slot confirmed holding data hashing to `hash`, if
+    /// any, returning that slot, which the new reference keeps from being reclaimed
+    pub fn dedup(&self, addr: usize, hash: Hash) -> Option<usize> {
+        let mut physical = self.physical.lock().unwrap();
+        let slot = physical.index.get(&hash).copied()?;
+        self.share(&mut physical, addr, slot);
+        Some(slot)
+    }
+
+    /// Let blocks writing data hashing to `hash` be deduplicated onto `slot` now that its write
+    /// reached the quorum, unless the slot was given other data since
+    pub fn confirm(&self, slot: usize, hash: Hash) {
+        let mut physical = self.physical.lock().unwrap();
+        if physical.hashes.get(&slot) == Some(&hash) {
+            physical.index.entry(hash).or_insert(slot);
+        }
+    }
+
+    /// Point the logical block `addr` at the existing `slot`, returning the slot it let go of
+    fn share(&self, physical: &mut Physical, addr: usize, slot: usize) -> Option<usize> {
+        if self.slot(addr) == Some(slot) || !physical.refs.contains_key(&slot) { return None }
+        let freed = self.unref(physical, addr);
+        self.shard(addr).write().unwrap().insert(addr, slot);
+        *physical.refs.entry(slot).or_default() += 1;
+        freed
+    }
+
+    /// Slot only the logical block `addr` refers to, to write data hashing to `hash` into,
+    /// allocated from the `physical` slots if `addr` has none or shares it
+    pub fn allocate(&self, addr: usize, hash: Hash, physical: usize) -> Option<usize> {
+        self.allocate_where(addr, hash, physical, |_| true)
+    }
+
+    /// Like `allocate`, preferring a new slot `fits` accepts
+    pub fn allocate_where(
+        &self, addr: usize, hash: Hash, physical: usize, fits: impl Fn(usize) -> bool
+    ) -> Option<usize> {
+        let mut held = self.physical.lock().unwrap();
+        if let Some(slot) = self.slot(addr) {
+            if held.refs.get(&slot) == Some(&1) {
+                reindex(&mut held, slot, hash);
+                return Some(slot)
+            }
+        }
+        let slot = self.alloc.take(physical, &self.owner, fits)?;
+        self.unref(&mut held, addr);
+        self.shard(addr).write().unwrap().insert(addr, slot);
+        held.refs.insert(slot, 1);
+        reindex(&mut held, slot, hash);
+        Some(slot)
+    }
+
+    /// Drop the reference of `addr` to its slot, returning the slot if nothing refers to it anymore
+    fn unref(&self, physical: &mut Physical, addr: usize) -> Option<usize> {
+        let slot = self.shard(addr).write().unwrap().remove(&addr)?;
+        let refs = physical.refs.get_mut(&slot)?;
+        *refs -= 1;
+        if *refs > 0 { return None }
+        physical.refs.remove(&slot);
+        self.heat[slot % SHARDS].lock().unwrap().remove(&slot);
+        if let Some(hash) = physical.hashes.remove(&slot) {
+            if physical.index.get(&hash) == Some(&slot) {
+                physical.index.remove(&hash);
+            }
+        }
+        self.alloc.give(slot);
+        Some(slot)
+    }
+
+    /// Count a read of `slot` towards its heat
+    pub fn touch(&self, slot: usize) {
+        *self.heat[slot % SHARDS].lock().unwrap().entry(slot).or_default() += 1;
+    }
+
+    /// Halve the heat of every slot so blocks no longer read cool down
+    pub fn cool(&self) {
+        for heat in self.heat.iter() {
+            heat.lock().unwrap().retain(|_, heat| {
+                *heat /= 2;
+                *heat > 0
+            });
+        }
+    }
+
+    /// Slots holding blocks with their heat, hottest first
+    pub fn hottest(&self) -> Vec<(usize, u64)> {
+        let physical = self.physical.lock().unwrap();
+        let mut slots: Vec<(usize, u64)> = physical.refs.keys()
+            .filter(|slot| !physical.reserved.contains(slot))
+            .map(|slot| (*slot, self.heat[slot % SHARDS].lock().unwrap().get(slot).copied().unwrap_or_default()))
+            .collect();
+        slots.sort_by_key(|(slot, heat)| (Reverse(*heat), *slot));
+        slots
+    }
+
+    /// Content hash of the data written to `slot`
+    pub fn hash(&self, slot: usize) -> Option<Hash> {
+        self.physical.lock().unwrap().hashes.get(&slot).copied()
+    }
+
+    /// Claim the free slot `to` from the pool for `relocate`
+    pub fn claim(&self, to: usize) -> bool {
+        self.alloc.claim(to, &self.owner)
+    }
+
+    /// Give back a slot claimed for a relocation that didn't happen
+    pub fn unclaim(&self, to: usize) {
+        self.alloc.give(to);
+    }
+
+    /// Point everything referring to `from`, snapshots included, at the claimed slot `to`
+    /// already holding the same data, giving `from` back to the pool, unless the data of
+    /// `from` no longer hashes to `hash`, returning whether it did
+    pub fn relocate(&self, from: usize, to: usize, hash: Option<Hash>) -> bool {
+        let mut physical = self.physical.lock().unwrap();
+        if physical.hashes.get(&from).copied() != hash { return false }
+        let Some(refs) = physical.refs.remove(&from) else { return true };
+        physical.refs.insert(to, refs);
+        let moved = |slots: &mut HashMap<usize, usize>| for slot in slots.values_mut() {
+            if *slot == from { *slot = to }
+        };
+        for shard in self.slots.iter() {
+            moved(&mut shard.write().unwrap());
+        }
+        for snapshot in physical.snapshots.iter_mut() {
+            moved(&mut snapshot.slots);
+        }
+        if let Some(hash) = physical.hashes.remove(&from) {
+            physical.hashes.insert(to, hash);
+            if physical.index.get(&hash) == Some(&from) {
+                physical.index.insert(hash, to);
+            }
+        }
+        if let Some(heat) = self.heat[from % SHARDS].lock().unwrap().remove(&from) {
+            self.heat[to % SHARDS].lock().unwrap().insert(to, heat);
+        }
+        self.alloc.give(from);
+        true
+    }
+
+    /// Make the logical block `addr` zero again, returning its slot if that was reclaimed
+    pub fn release(&self, addr: usize) -> Option<usize> {
+        self.unref(&mut self.physical.lock().unwrap(), addr)
+    }
+
+    /// Every logical block mapped with its slot
+    fn mapping(&self) -> HashMap<usize, usize> {
+        self.slots.iter().flat_map(|shard| shard.read().unwrap().clone()).collect()
+    }
+
+    /// Freeze the current mapping as `name`, every slot it refers to gains a reference
+    /// so writes to the live blocks get new slots instead of overwriting the frozen ones
+    pub fn snapshot(&self, name: &str) -> io::Result<()> {
+        let mut physical = self.physical.lock().unwrap();
+        if physical.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("snapshot \"{name}\" already exists")));
+        }
+        let slots = self.mapping();
+        for slot in slots.values() {
+            *physical.refs.entry(*slot).or_default() += 1;
+        }
+        physical.snapshots.push(Snapshot {
+            name: name.to_string(),
+            created: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                .map(|since| since.as_secs()).unwrap_or_default(),
+            slots,
+        });
+        Ok(())
+    }
+
+    pub fn snapshots(&self) -> Vec<Snapshot> {
+        self.physical.lock().unwrap().snapshots.clone()
+    }
+
+    /// Make the live mapping that of the snapshot `name` again
+    pub fn rollback(&self, name: &str) -> io::Result<()> {
+        let mut physical = self.physical.lock().unwrap();
+        let Some(snapshot) = physical.snapshots.iter().find(|snapshot| snapshot.name == name) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("no snapshot named \"{name}\"")));
+        };
+        let slots = snapshot.slots.clone();
+        for addr in self.mapping().into_keys() {
+            self.unref(&mut physical, addr);
+        }
+        for (addr, slot) in slots {
+            self.shard(addr).write().unwrap().insert(addr, slot);
+            *physical.refs.entry(slot).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Physical slots in use
+    pub fn allocated(&self) -> usize {
+        self.physical.lock().unwrap().refs.len()
+    }
+
+    /// Logical blocks holding data
+    pub fn mapped(&self) -> usize {
+        self.slots.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+}
+
+/// Record `slot` as about to hold data hashing to `hash`, leaving it out of the index until
+/// `confirm` as it only holds that data once the write reaches its quorum
+fn reindex(physical: &mut Physical, slot: usize, hash: Hash) {
+    if let Some(old) = physical.hashes.insert(slot, hash) {
+        if physical.index.get(&old) == Some(&slot) {
+            physical.index.remove(&old);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ownership() {
+        let alloc = Arc::new(Allocator::default());
+        let one = BlockMap::with_allocator(alloc.clone(), "one");
+        let two = BlockMap::with_allocator(alloc.clone(), "two");
+        assert_eq!(one.allocate(0, [1; 32], 4), Some(0));
+        assert_eq!(two.allocate(0, [2; 32], 4), Some(1));
+        assert_eq!(one.allocate(1, [3; 32], 4), Some(2));
+        assert!(one.claim(3));
+        assert_eq!(two.allocate(1, [4; 32], 4), None);
+
+        let saved: Saved = serde_json::from_str(&serde_json::to_string(&*alloc).unwrap()).unwrap();
+        assert_eq!(saved.owners.values().filter(|owner| *owner == "one").count(), 3);
+        alloc.replace(Allocator::from(saved));
+
+        // Restarted with nothing mapped, only the slots of "one" go back to the pool
+        let one = BlockMap::with_allocator(alloc.clone(), "one");
+        assert_eq!(one.reclaim(), 3);
+        assert!(alloc.taken(1));
+        assert_eq!(alloc.free(4), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn thin() {
+        let map = BlockMap::new();
+        assert!(map.zero(1000));
+        assert_eq!(map.slot(1000), None);
+        assert_eq!(map.allocate(1000, [1; 32], 2), Some(0));
+        assert!(!map.zero(1000));
+        assert_eq!(map.allocate(1000, [2; 32], 2), Some(0));
+        assert_eq!(map.allocate(3, [3; 32], 2), Some(1));
+        assert_eq!(map.allocate(4, [4; 32], 2), None);
+        assert_eq!((map.mapped(), map.allocated()), (2, 2));
+
+        assert_eq!(map.release(1000), Some(0));
+        assert!(map.zero(1000));
+        assert_eq!(map.release(1000), None);
+        assert_eq!((map.mapped(), map.allocated()), (1, 1));
+    }
+
+    #[test]
+    fn quarantine() {
+        let map = BlockMap::new();
+        assert_eq!(map.allocate(0, [1; 32], 2), Some(0));
+        assert_eq!(map.release(0), Some(0));
+        // Echoes of the released slot may still be in flight, so another one is handed out
+        assert_eq!(map.allocate(1, [2; 32], 2), Some(1));
+        assert_eq!(map.allocate(2, [3; 32], 2), None);
+        map.alloc.shards[0].lock().unwrap().quarantine[0].0 -= QUARANTINE;
+        assert_eq!(map.allocate(2, [3; 32], 2), Some(0));
+    }
+
+    #[test]
+    fn dedup() {
+        let map = BlockMap::new();
+        assert_eq!(hash(b"block"), hash(b"block"));
+        assert_ne!(hash(b"block"), hash(b"other"));
+        assert_eq!(map.dedup(0, hash(b"block")), None);
+        assert_eq!(map.allocate(0, hash(b"block"), 2), Some(0));
+        // Not onto a slot whose write may still miss its quorum
+        assert_eq!(map.dedup(1, hash(b"block")), None);
+        map.confirm(0, hash(b"block"));
+        assert_eq!(map.dedup(1, hash(b"block")), Some(0));
+        assert_eq!((map.mapped(), map.allocated()), (2, 1));
+
+        // The shared slot outlives the block that first wrote it
+        assert_eq!(map.release(0), None);
+        assert_eq!(map.slot(1), Some(0));
+        // Rewriting the block moves it off the shared slot only once nothing else refers to it
+        assert_eq!(map.allocate(1, hash(b"other"), 2), Some(0));
+        assert_eq!(map.dedup(2, hash(b"block")), None);
+        // Nor is a confirmation of the data it held before taken for the new one
+        map.confirm(0, hash(b"block"));
+        assert_eq!(map.dedup(2, hash(b"block")), None);
+        map.confirm(0, hash(b"other"));
+        assert_eq!(map.dedup(2, hash(b"other")), Some(0));
+    }
+
+    #[test]
+    fn saved() {
+        let alloc = Arc::new(Allocator::default());
+        let map = BlockMap::with_allocator(alloc.clone(), "one");
+        map.reserve(1, 8).unwrap();
+        assert_eq!(map.allocate(5, hash(b"block"), 8), Some(1));
+        map.confirm(1, hash(b"block"));
+        assert_eq!(map.dedup(6, hash(b"block")), Some(1));
+
+        let map: BlockMap = serde_json::from_str(&serde_json::to_string(&map).unwrap()).unwrap();
+        let map = map.restore(alloc.clone(), "one");
+        assert_eq!(map.reclaim(), 0);
+        assert_eq!(map.reserved(), &[0]);
+        assert_eq!((map.slot(5), map.slot(6)), (Some(1), Some(1)));
+        assert!(map.zero(7) && !map.zero(6));
+        assert_eq!(map.dedup(7, hash(b"block")), Some(1));
+        assert_eq!(map.allocate(8, hash(b"other"), 8), Some(2));
+    }
+
+    #[test]
+    fn saved_snapshots() {
+        let map = BlockMap::new();
+        assert_eq!(map.allocate(0, hash(b"old"), 4), Some(0));
+        map.snapshot("before").unwrap();
+        assert_eq!(map.allocate(0, hash(b"new"), 4), Some(1));
+
+        let map: BlockMap = serde_json::from_str(&serde_json::to_string(&map).unwrap()).unwrap();
+        let map = map.restore(Arc::new(Allocator::default()), "default");
+        assert_eq!(map.snapshots().len(), 1);
+        assert_eq!(map.snapshots()[0].blocks(), 1);
+        map.rollback("before").unwrap();
+        assert_eq!(map.slot(0), Some(0));
+        assert_eq!(map.allocated(), 1);
+    }
+
+    #[test]
+    fn fitting() {
+        let map = BlockMap::new();
+        // Odd slots share destinations with the stripe being written
+        assert_eq!(map.allocate_where(0, [1; 32], 6, |slot| slot % 2 == 0), Some(0));
+        assert_eq!(map.allocate_where(1, [2; 32], 6, |slot| slot % 2 == 0), Some(2));
+        assert_eq!(map.release(0), Some(0));
+        map.alloc.shards[0].lock().unwrap().quarantine[0].0 -= QUARANTINE;
+        assert_eq!(map.allocate_where(2, [3; 32], 6, |slot| slot % 2 == 0), Some(4));
+        assert_eq!(map.allocate_where(3, [4; 32], 6, |slot| slot % 2 == 0), Some(0));
+        // Without a fitting slot left any free one does
+        assert_eq!(map.allocate_where(4, [5; 32], 6, |slot| slot % 2 == 0), Some(5));
+        assert_eq!(map.allocated(), 4);
+    }
+
+    #[test]
+    fn concurrent() {
+        let alloc = Arc::new(Allocator::default());
+        let map = Arc::new(BlockMap::with_allocator(alloc.clone(), "one"));
+        let threads: Vec<_> = (0..8).map(|thread| {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                (0..100).map(|i| {
+                    let addr = thread * 100 + i;
+                    map.allocate(addr, hash(&addr.to_be_bytes()), 1000).unwrap()
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        let mut slots: Vec<usize> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        slots.sort();
+        slots.dedup();
+        assert_eq!(slots.len(), 800);
+        assert_eq!((map.mapped(), map.allocated()), (800, 800));
+        assert_eq!(alloc.free(1000).len(), 200);
+
+        // Every shard's slots survive a save and load
+        let saved: Allocator = serde_json::from_str(&serde_json::to_string(&*alloc).unwrap()).unwrap();
+        assert_eq!(saved.free(1000), alloc.free(1000));
+        assert!(slots.iter().all(|slot| saved.taken(*slot)));
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::{fd::{AsRawFd, RawFd}, unix::net::UnixStream},
+    thread};
+
+use log::{trace, debug, info, error};
+use crate::buf::Buf;
+
+const MAGIC: &[u8; 8] = b"NBDMAGIC";
+const IHAVEOPT: u64 = 0x49484156454f5054; // Magic of options and of the newstyle handshake
+const REPLY_MAGIC: u64 = 0x3e889045565a9; // Magic of option replies
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const FIXED_NEWSTYLE: u16 = 1 << 0;
+const NO_ZEROES: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = 1 << 31 | 1;
+const REP_ERR_INVALID: u32 = 1 << 31 | 3;
+const REP_ERR_UNKNOWN: u32 = 1 << 31 | 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const HAS_FLAGS: u16 = 1 << 0;
+const SEND_FLUSH: u16 = 1 << 2;
+const SEND_FUA: u16 = 1 << 3;
+const SEND_TRIM: u16 = 1 << 5;
+const SEND_WRITE_ZEROES: u16 = 1 << 6;
+/// Transmission flags of every export
+const FLAGS: u16 = HAS_FLAGS | SEND_FLUSH | SEND_FUA | SEND_TRIM | SEND_WRITE_ZEROES;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+
+const CMD_FLAG_FUA: u16 = 1 << 0;
+
+const MAX_OPTION: usize = 4096; // Longest option data accepted during the handshake
+const MAX_REQUEST: u32 = 32 << 20; // Longest read or write accepted
+const SECTOR: u64 = 512; // Block size the kernel device is set up with
+
+// Kernel NBD ioctls
+const NBD_SET_SOCK: u64 = 0xab00;
+const NBD_SET_BLKSIZE: u64 = 0xab01;
+const NBD_DO_IT: u64 = 0xab03;
+const NBD_CLEAR_SOCK: u64 = 0xab04;
+const NBD_CLEAR_QUE: u64 = 0xab05;
+const NBD_SET_SIZE_BLOCKS: u64 = 0xab07;
+const NBD_SET_FLAGS: u64 = 0xab0a;
+
+/// A block device exported over NBD
+pub trait Blocks {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()>;
+    /// Write `buf` at `off`, handed over so its blocks can be kept without copying them
+    fn write_at(&self, buf: Buf, off: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Write that's durable once it returns, however writes are cached
+    fn write_fua(&self, buf: Buf, off: u64) -> io::Result<()> {
+        self.write_at(buf, off)?;
+        self.flush()
+    }
+    /// Discard `len` bytes at `off`, reading them back afterwards may return anything
+    fn trim(&self, _off: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+    fn write_zeroes(&self, off: u64, len: u64) -> io::Result<()> {
+        self.write_at(Buf::zeroed(len as usize), off)
+    }
+}
+
+/// Serve `exports` by name to NBD clients connecting to `addr`, a thread per connection
+pub fn serve<B>(exports: BTreeMap<String, B>, addr: &str) -> io::Result<thread::JoinHandle<()>>
+where B: Blocks + Clone + Send + Sync + 'static {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving {} NBD exports on \"{addr}\"", exports.len());
+    Ok(thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream {
+            Err(err) => {
+                error!("Accepting NBD connection: {err:?}");
+                continue
+            },
+            Ok(stream) => stream,
+        };
+        let exports = exports.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+            match client(stream, &exports) {
+                Err(err) => error!("Serving NBD client \"{peer}\": {err:?}"),
+                Ok(()) => debug!("NBD client \"{peer}\" disconnected"),
+            }
+        });
+    }))
+}
+
+/// Attach `blocks` to the kernel NBD `device`, answering its requests on a socket pair
+pub fn attach<B>(device: &str, blocks: B) -> io::Result<thread::JoinHandle<()>>
+where B: Blocks + Send + 'static {
+    let dev = OpenOptions::new().read(true).write(true).open(device)?;
+    let (kernel, ours) = UnixStream::pair()?;
+    let fd = dev.as_raw_fd();
+    ioctl(fd, NBD_SET_BLKSIZE, SECTOR)?;
+    ioctl(fd, NBD_SET_SIZE_BLOCKS, blocks.size()? / SECTOR)?;
+    ioctl(fd, NBD_SET_FLAGS, FLAGS as u64)?;
+    ioctl(fd, NBD_CLEAR_SOCK, 0)?;
+    ioctl(fd, NBD_SET_SOCK, kernel.as_raw_fd() as u64)?;
+    let device = device.to_string();
+    thread::spawn(move || {
+        if let Err(err) = ioctl(dev.as_raw_fd(), NBD_DO_IT, 0) {
+            error!("Running NBD device \"{device}\": {err:?}");
+        }
+        ioctl(dev.as_raw_fd(), NBD_CLEAR_QUE, 0).ok();
+        ioctl(dev.as_raw_fd(), NBD_CLEAR_SOCK, 0).ok();
+        drop(kernel);
+    });
+    let reader = BufReader::new(ours.try_clone()?);
+    Ok(thread::spawn(move || if let Err(err) = transmit(reader, BufWriter::new(ours), &blocks) {
+        error!("Serving NBD device: {err:?}");
+    }))
+}
+
+fn ioctl(fd: RawFd, request: u64, arg: u64) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, request as _, arg) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Negotiate an export with a client and answer its requests until it disconnects
+fn client<B: Blocks + Clone>(stream: TcpStream, exports: &BTreeMap<String, B>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    match handshake(&mut reader, &mut writer, exports)? {
+        None => Ok(()),
+        Some(blocks) => transmit(reader, writer, &blocks),
+    }
+}
+
+/// The export named `name`, an empty name is the default one
+fn export<'a, B>(exports: &'a BTreeMap<String, B>, name: &str) -> Option<&'a B> {
+    match name {
+        "" if exports.len() == 1 => exports.values().next(),
+        "" => exports.get("default"),
+        name => exports.get(name),
+    }
+}
+
+/// Fixed newstyle negotiation, the export picked or `None` if the client gave up
+fn handshake<B: Blocks + Clone>(
+    reader: &mut impl Read, writer: &mut impl Write, exports: &BTreeMap<String, B>,
+) -> io::Result<Option<B>> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&IHAVEOPT.to_be_bytes())?;
+    writer.write_all(&(FIXED_NEWSTYLE | NO_ZEROES).to_be_bytes())?;
+    writer.flush()?;
+    let zeroes = read_u32(reader)? & NO_ZEROES as u32 == 0;
+    loop {
+        if read_u64(reader)? != IHAVEOPT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad option magic"));
+        }
+        let opt = read_u32(reader)?;
+        let len = read_u32(reader)? as usize;
+        if len > MAX_OPTION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("option of {len} bytes")));
+        }
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+        trace!("NBD option {opt} with {len} bytes");
+        match opt {
+            OPT_EXPORT_NAME => {
+                let name = String::from_utf8_lossy(&data);
+                let Some(blocks) = export(exports, &name) else {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("no export named \"{name}\"")));
+                };
+                writer.write_all(&blocks.size()?.to_be_bytes())?;
+                writer.write_all(&FLAGS.to_be_bytes())?;
+                if zeroes {
+                    writer.write_all(&[0; 124])?;
+                }
+                writer.flush()?;
+                return Ok(Some(blocks.clone()))
+            },
+            OPT_ABORT => {
+                reply(writer, opt, REP_ACK, &[])?;
+                return Ok(None)
+            },
+            OPT_LIST => {
+                for name in exports.keys() {
+                    let mut entry = (name.len() as u32).to_be_bytes().to_vec();
+                    entry.extend_from_slice(name.as_bytes());
+                    reply(writer, opt, REP_SERVER, &entry)?;
+                }
+                reply(writer, opt, REP_ACK, &[])?;
+            },
+            OPT_INFO | OPT_GO => {
+                let name = data.get(..4)
+                    .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+                    .and_then(|len| data.get(4..4 + len));
+                let Some(name) = name else {
+                    reply(writer, opt, REP_ERR_INVALID, b"malformed export name")?;
+                    continue
+                };
+                let name = String::from_utf8_lossy(name);
+                let Some(blocks) = export(exports, &name) else {
+                    reply(writer, opt, REP_ERR_UNKNOWN, format!("no export named \"{name}\"").as_bytes())?;
+                    continue
+                };
+                let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend_from_slice(&blocks.size()?.to_be_bytes());
+                info.extend_from_slice(&FLAGS.to_be_bytes());
+                reply(writer, opt, REP_INFO, &info)?;
+                let mut info = INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                for size in [1, SECTOR as u32, MAX_REQUEST] {
+                    info.extend_from_slice(&size.to_be_bytes());
+                }
+                reply(writer, opt, REP_INFO, &info)?;
+                reply(writer, opt, REP_ACK, &[])?;
+                if opt == OPT_GO {
+                    return Ok(Some(blocks.clone()))
+                }
+            },
+            _ => reply(writer, opt, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn reply(writer: &mut impl Write, opt: u32, kind: u32, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&REPLY_MAGIC.to_be_bytes())?;
+    writer.write_all(&opt.to_be_bytes())?;
+    writer.write_all(&kind.to_be_bytes())?;
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+/// Answer the requests of a client of `blocks` until it disconnects
+pub fn transmit<B: Blocks>(mut reader: impl Read, mut writer: impl Write, blocks: &B) -> io::Result<()> {
+    let mut request = [0; 28];
+    let mut data = vec![];
+    loop {
+        reader.read_exact(&mut request)?;
+        let field = |range: std::ops::Range<usize>| request[range].iter().fold(0, |acc, byte| acc << 8 | *byte as u64);
+        if field(0..4) as u32 != REQUEST_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad request magic"));
+        }
+        let (flags, cmd) = (field(4..6) as u16, field(6..8) as u16);
+        let (handle, off, len) = (field(8..16), field(16..24), field(24..28) as u32);
+        if matches!(cmd, CMD_READ | CMD_WRITE) && len > MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("request of {len} bytes")));
+        }
+        data.resize(if cmd == CMD_READ { len as usize } else { 0 }, 0);
+        // Written data is read into a buffer of its own, which the blocks it covers are cut out of
+        let mut written = Buf::zeroed(if cmd == CMD_WRITE { len as usize } else { 0 });
+        if cmd == CMD_WRITE {
+            reader.read_exact(written.make_mut())?;
+        }
+        trace!("NBD command {cmd} of {len} bytes at {off}");
+        let res = match cmd {
+            CMD_DISC => return Ok(()),
+            _ if off.checked_add(len as u64).is_none_or(|end| end > blocks.size().unwrap_or_default()) =>
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "request past the end of the export")),
+            CMD_READ => blocks.read_at(&mut data, off),
+            CMD_WRITE if flags & CMD_FLAG_FUA != 0 => blocks.write_fua(written, off),
+            CMD_WRITE => blocks.write_at(written, off),
+            CMD_FLUSH => blocks.flush(),
+            CMD_TRIM => blocks.trim(off, len as u64),
+            CMD_WRITE_ZEROES => blocks.write_zeroes(off, len as u64)
+                .and_then(|_| if flags & CMD_FLAG_FUA != 0 { blocks.flush() } else { Ok(()) }),
+            _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+        if let Err(err) = &res {
+            debug!("NBD command {cmd} of {len} bytes at {off}: {err}");
+        }
+        writer.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+        writer.write_all(&res.as_ref().map_or_else(errno, |_| 0).to_be_bytes())?;
+        writer.write_all(&handle.to_be_bytes())?;
+        if cmd == CMD_READ && res.is_ok() {
+            writer.write_all(&data)?;
+        }
+        writer.flush()?;
+    }
+}
+
+/// Error number to tell a client about `err`, out of those NBD allows
+fn errno(err: &io::Error) -> u32 {
+    match err.raw_os_error() {
+        Some(errno @ (libc::EPERM | libc::EIO | libc::ENOMEM | libc::EINVAL | libc::ENOSPC | libc::EOVERFLOW)) => errno as u32,
+        _ => match err.kind() {
+            io::ErrorKind::InvalidInput => libc::EINVAL as u32,
+            io::ErrorKind::Unsupported => libc::ENOTSUP as u32,
+            _ => libc::EIO as u32,
+        },
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Memory(Arc<Mutex<Vec<u8>>>);
+
+    impl Blocks for Memory {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+            let off = off as usize;
+            buf.copy_from_slice(&self.0.lock().unwrap()[off..off + buf.len()]);
+            Ok(())
+        }
+
+        fn write_at(&self, buf: Buf, off: u64) -> io::Result<()> {
+            let off = off as usize;
+            self.0.lock().unwrap()[off..off + buf.len()].copy_from_slice(&buf);
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.lock().unwrap().len() as u64)
+        }
+    }
+
+    fn request(stream: &mut UnixStream, cmd: u16, handle: u64, off: u64, len: u32) {
+        stream.write_all(&REQUEST_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&0u16.to_be_bytes()).unwrap();
+        stream.write_all(&cmd.to_be_bytes()).unwrap();
+        stream.write_all(&handle.to_be_bytes()).unwrap();
+        stream.write_all(&off.to_be_bytes()).unwrap();
+        stream.write_all(&len.to_be_bytes()).unwrap();
+    }
+
+    /// Error and handle of the next simple reply
+    fn response(stream: &mut UnixStream) -> (u32, u64) {
+        assert_eq!(read_u32(stream).unwrap(), SIMPLE_REPLY_MAGIC);
+        (read_u32(stream).unwrap(), read_u64(stream).unwrap())
+    }
+
+    #[test]
+    fn transmission() {
+        let memory = Memory(Arc::new(Mutex::new(vec![0; 4096])));
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let blocks = memory.clone();
+        let served = thread::spawn(move || transmit(server.try_clone().unwrap(), server, &blocks));
+
+        request(&mut client, CMD_WRITE, 1, 100, 5);
+        client.write_all(b"blues").unwrap();
+        assert_eq!(response(&mut client), (0, 1));
+        assert_eq!(&memory.0.lock().unwrap()[100..105], b"blues");
+
+        request(&mut client, CMD_READ, 2, 98, 8);
+        assert_eq!(response(&mut client), (0, 2));
+        let mut data = [0; 8];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"\0\0blues\0");
+
+        request(&mut client, CMD_READ, 3, 4090, 8);
+        assert_eq!(response(&mut client), (libc::EINVAL as u32, 3));
+        request(&mut client, CMD_FLUSH, 4, 0, 0);
+        assert_eq!(response(&mut client), (0, 4));
+        request(&mut client, CMD_DISC, 5, 0, 0);
+        served.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn negotiation() {
+        let exports = BTreeMap::from([
+            ("default".to_string(), Memory(Arc::new(Mutex::new(vec![0; 512])))),
+            ("other".to_string(), Memory(Arc::new(Mutex::new(vec![0; 1024])))),
+        ]);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let negotiated = thread::spawn(move || {
+            handshake(&mut server.try_clone().unwrap(), &mut &server, &exports).map(|blocks| blocks.map(|blocks| blocks.size().unwrap()))
+        });
+        let mut magic = [0; 8];
+        client.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, MAGIC);
+        assert_eq!(read_u64(&mut client).unwrap(), IHAVEOPT);
+        client.read_exact(&mut [0; 2]).unwrap();
+        client.write_all(&(NO_ZEROES as u32).to_be_bytes()).unwrap();
+
+        let option = |client: &mut UnixStream, opt: u32, data: &[u8]| {
+            client.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+            client.write_all(&opt.to_be_bytes()).unwrap();
+            client.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+            client.write_all(data).unwrap();
+        };
+        // Reply type and data of the next option reply
+        let reply = |client: &mut UnixStream| {
+            assert_eq!(read_u64(client).unwrap(), REPLY_MAGIC);
+            read_u32(client).unwrap();
+            let kind = read_u32(client).unwrap();
+            let mut data = vec![0; read_u32(client).unwrap() as usize];
+            client.read_exact(&mut data).unwrap();
+            (kind, data)
+        };
+
+        option(&mut client, OPT_LIST, &[]);
+        assert_eq!(reply(&mut client), (REP_SERVER, b"\0\0\0\x07default".to_vec()));
+        assert_eq!(reply(&mut client), (REP_SERVER, b"\0\0\0\x05other".to_vec()));
+        assert_eq!(reply(&mut client).0, REP_ACK);
+
+        option(&mut client, OPT_GO, b"\0\0\0\x07missing\0\0");
+        assert_eq!(reply(&mut client).0, REP_ERR_UNKNOWN);
+
+        option(&mut client, OPT_GO, b"\0\0\0\x05other\0\0");
+        let (kind, info) = reply(&mut client);
+        assert_eq!((kind, &info[..10]), (REP_INFO, &[0, 0, 0, 0, 0, 0, 0, 0, 4, 0][..]));
+        assert_eq!(reply(&mut client).0, REP_INFO);
+        assert_eq!(reply(&mut client).0, REP_ACK);
+        assert_eq!(negotiated.join().unwrap().unwrap(), Some(1024));
+    }
+}
@@ -0,0 +1,33 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+use crate::{json, map::{Allocator, BlockMap}};
+
+/// Which destinations hold the replicas of every physical slot
+#[derive(Deserialize, Serialize, Default)]
+pub struct Placement {
+    /// Replica destinations of each slot, indexed by slot
+    pub slots: Vec<Vec<IpAddr>>,
+    /// Slots handed out and the volumes they belong to
+    #[serde(default)]
+    pub alloc: Allocator,
+    /// Logical blocks of every volume and the slots holding them
+    #[serde(default)]
+    pub volumes: BTreeMap<String, BlockMap>,
+}
+
+impl Placement {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Save by writing a temporary file and renaming it over `file_name`,
+    /// so a crash never leaves a half written placement map behind
+    pub fn save(&self, file_name: &str) -> std::io::Result<()> {
+        json::save(file_name, self)
+    }
+
+    pub fn load(file_name: &str) -> Option<Self> {
+        json::load(file_name, "placement map")
+    }
+}
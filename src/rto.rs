@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::Duration};
+
+use crate::TIMEOUT;
+
+const INITIAL: Duration = Duration::from_secs(1); // Timeout before anything is known about a destination
+const MIN: Duration = Duration::from_millis(200); // Shortest timeout, so jitter isn't taken for loss
+const MAX: Duration = Duration::from_secs(60); // Longest timeout when there's no global one
+
+static ESTIMATES: LazyLock<Mutex<HashMap<IpAddr, Estimate>>> = LazyLock::new(Default::default);
+
+/// Smoothed round trip time and its variance for a destination, as TCP estimates them
+#[derive(Default, Clone, Copy, Debug)]
+struct Estimate {
+    srtt: Duration,
+    rttvar: Duration,
+    backoff: u32, // Timeouts in a row since the last sample
+    sampled: bool,
+}
+
+impl Estimate {
+    fn sample(&mut self, rtt: Duration) {
+        if self.srtt.is_zero() {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2;
+        } else {
+            self.rttvar = (self.rttvar * 3 + self.srtt.abs_diff(rtt)) / 4;
+            self.srtt = (self.srtt * 7 + rtt) / 8;
+        }
+        self.backoff = 0;
+    }
+
+    fn timeout(&self) -> Duration {
+        let max = unsafe { TIMEOUT }.unwrap_or(MAX);
+        let rto = match self.srtt.is_zero() {
+            true => INITIAL,
+            false => self.srtt + self.rttvar * 4,
+        };
+        rto.saturating_mul(1 << self.backoff.min(16)).clamp(MIN, max.max(MIN))
+    }
+}
+
+/// Start the estimate for `ip` off at `rtt`, unless replies from it were already timed
+pub fn seed(ip: IpAddr, rtt: Duration) {
+    let mut estimates = ESTIMATES.lock().unwrap();
+    let estimate = estimates.entry(ip).or_default();
+    if !estimate.sampled {
+        estimate.srtt = rtt;
+        estimate.rttvar = rtt / 2;
+    }
+}
+
+/// Fold the round trip time `rtt` of a reply from `ip` into its estimate
+pub fn sample(ip: IpAddr, rtt: Duration) {
+    let mut estimates = ESTIMATES.lock().unwrap();
+    let estimate = estimates.entry(ip).or_default();
+    estimate.sample(rtt);
+    estimate.sampled = true;
+}
+
+/// Double the timeout of `ip` after an echo to it timed out, until the next reply
+pub fn backoff(ip: IpAddr) {
+    ESTIMATES.lock().unwrap().entry(ip).or_default().backoff += 1;
+}
+
+/// How long to wait for a reply from `ip` before taking the echo as lost
+pub fn timeout(ip: IpAddr) -> Duration {
+    ESTIMATES.lock().unwrap().get(&ip).copied().unwrap_or_default().timeout()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn estimate() {
+        let mut estimate = Estimate::default();
+        assert_eq!(estimate.timeout(), INITIAL);
+        estimate.sample(100 * MS);
+        assert_eq!((estimate.srtt, estimate.rttvar), (100 * MS, 50 * MS));
+        assert_eq!(estimate.timeout(), 300 * MS);
+        // Later samples are smoothed in, the variance tracking how far they stray
+        estimate.sample(180 * MS);
+        assert_eq!((estimate.srtt, estimate.rttvar), (110 * MS, Duration::from_micros(57500)));
+        estimate.sample(MS);
+        assert!(estimate.srtt < 110 * MS && estimate.rttvar > 57 * MS);
+    }
+
+    #[test]
+    fn backed_off() {
+        let mut estimate = Estimate::default();
+        estimate.sample(100 * MS);
+        estimate.backoff = 2;
+        assert_eq!(estimate.timeout(), 1200 * MS);
+        // Timeouts stay within bounds however far they're backed off or however fast replies come
+        estimate.backoff = 40;
+        assert_eq!(estimate.timeout(), MAX);
+        estimate.sample(100 * MS);
+        assert_eq!(estimate.backoff, 0);
+        let mut fast = Estimate::default();
+        fast.sample(MS);
+        assert_eq!(fast.timeout(), MIN);
+    }
+
+    #[test]
+    fn seeded() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20));
+        seed(ip, 100 * MS);
+        assert_eq!(timeout(ip), 300 * MS);
+        backoff(ip);
+        assert_eq!(timeout(ip), 600 * MS);
+        // Replies timed since outweigh what the destination was seeded with
+        sample(ip, 400 * MS);
+        seed(ip, 10 * MS);
+        assert_eq!(timeout(ip), Duration::from_micros(587500));
+    }
+}
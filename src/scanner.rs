@@ -1,6 +1,6 @@
 use std::{
-    collections::HashMap,
-    sync::{mpsc, Mutex, Arc},
+    collections::{HashMap, VecDeque},
+    sync::{atomic::{AtomicUsize, Ordering}, mpsc, Mutex, Arc},
     time::{Duration, Instant},
     net::{Ipv4Addr, IpAddr},
     thread::sleep, //io,
@@ -8,16 +8,19 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 use log::{trace, debug, info, error};
-use icmp::IcmpSocket;
 use tokio::{task, };
 
 use crate::{
+    lifecycle::{State, Event},
+    filter,
+    socket::{self, batch, MTU},
     RESPONSE_SIZE,
-    ICMP_PACKET, PACKET_SIZE, SIZE,
+    ICMP_PACKET, SIZE,
     IPStore, checksum, connect,
     rand_ip};
 
 static PROBE: [u8; SIZE] = [0x66; SIZE];
+const LOST: Duration = Duration::from_secs(1); // Probes unanswered this long no longer count as in flight
 
 #[derive(Clone)]
 pub struct Scanner {
@@ -32,6 +35,13 @@ pub struct Destination {
     pub round_trip: Duration,
     pub small: bool,
     pub ip: Ipv4Addr,
+    #[serde(default = "legacy")]
+    pub state: State,
+}
+
+/// State of destinations saved before their lifecycle was kept, they already held data
+fn legacy() -> State {
+    State::Active
 }
 
 struct PingResponse {
@@ -40,6 +50,7 @@ struct PingResponse {
     pub corrupt: bool,
     pub ip: Ipv4Addr,
     pub small: bool,
+    pub unreachable: bool, // An ICMP error came back instead of a reply
 }
 
 //pub type PingResult = Result<PingResponse, io::Error>;
@@ -81,20 +92,48 @@ impl Scanner {
 
     pub async fn mass_scan(&mut self, throttle: usize, parallel: usize, limit: usize, rand: bool) {
         let (tx, rx) = mpsc::channel();
-        let listner = task::spawn(listner(rx));
+        let answered = Arc::new(AtomicUsize::new(0));
+        let listner = task::spawn(listner(rx, answered.clone()));
         info!("Starting mass scan with a limit of {} and {} to randomized IP order!", limit, rand);
         let duration = Duration::from_millis(throttle as u64);
         let mut futs: Vec<task::JoinHandle<()>> = vec![];
+        let mut sender = match batch() {
+            1 => None,
+            _ => Some(socket::raw().and_then(socket::Sender::new).expect("Unable to open ICMP socket")),
+        };
+        let mut batched = vec![];
+        let mut in_flight = InFlight { sent: VecDeque::new(), answered };
+        // Batches hold at most `parallel` probes, and none is held back while throttled
+        let per_batch = match throttle {
+            0 => batch().min(parallel).max(1),
+            _ => 1,
+        };
 
         for _ in 0..limit {
-            if futs.len() >= parallel {
-                pop_futs(&mut futs).await;
-            }
             let ip = self.next_ip();
-            futs.push(task::spawn(ping(ip, &PROBE)));
+            match &mut sender {
+                None => {
+                    if futs.len() >= parallel {
+                        pop_futs(&mut futs).await;
+                    }
+                    futs.push(task::spawn(ping(ip, &PROBE)));
+                },
+                Some(sender) => {
+                    batched.push(ip);
+                    if batched.len() >= per_batch {
+                        in_flight.wait(parallel.saturating_sub(batched.len()));
+                        in_flight.sent(batched.len());
+                        self.ping_batch(sender, &mut batched);
+                    }
+                },
+            }
             self.timings.lock().unwrap().insert(ip, Instant::now());
             sleep(duration);
         }
+        if let Some(sender) = &mut sender {
+            in_flight.wait(parallel.saturating_sub(batched.len()));
+            self.ping_batch(sender, &mut batched);
+        }
         info!("Done pinging");
         while !futs.is_empty() { pop_futs(&mut futs).await; }
         if let Err(err) = tx.send(()) {
@@ -104,6 +143,10 @@ impl Scanner {
         let pings = listner.await.unwrap();
         info!("Handling responeses");
         for ping in pings {
+            if ping.unreachable {
+                self.dead.push(ping.ip);
+                continue
+            }
             if ping.corrupt || ping.small {
                 self.dead.push(ping.ip);
             }
@@ -118,28 +161,88 @@ impl Scanner {
             };
             self.dsts.push(Destination {
                 round_trip: ping.finish.duration_since(start),
-                small: ping.small, ip: ping.ip
+                small: ping.small, ip: ping.ip,
+                state: State::Candidate.next(Event::Scanned(!ping.corrupt && !ping.small)),
             });
         }
     }
 }
 
+impl Scanner {
+    /// Ping all of `ips` as a single batch through `sender`, timing them from now
+    fn ping_batch(&self, sender: &mut socket::Sender, ips: &mut Vec<Ipv4Addr>) {
+        let count = ips.len();
+        for ip in ips.iter() {
+            sender.push(IpAddr::V4(*ip), |buf| write_probe(buf, *ip, &PROBE));
+        }
+        let now = Instant::now();
+        let mut timings = self.timings.lock().unwrap();
+        for ip in ips.drain(..) {
+            timings.insert(ip, now);
+        }
+        sender.flush();
+        debug!("Sent a batch of {count} ping packets");
+    }
+}
+
+/// Batched probes sent and not answered yet, so no more than `parallel` are in flight at once
+/// as with probes sent one at a time
+struct InFlight {
+    sent: VecDeque<Instant>,
+    answered: Arc<AtomicUsize>, // Replies the listener received and weren't taken off `sent` yet
+}
+
+impl InFlight {
+    fn sent(&mut self, count: usize) {
+        let now = Instant::now();
+        self.sent.extend(std::iter::repeat_n(now, count));
+    }
+
+    /// Wait until at most `max` probes are in flight, taking those answered off the oldest
+    /// sent and giving up on those unanswered for `LOST`
+    fn wait(&mut self, max: usize) {
+        loop {
+            let answered = self.answered.swap(0, Ordering::Relaxed).min(self.sent.len());
+            self.sent.drain(..answered);
+            while self.sent.front().is_some_and(|sent| sent.elapsed() >= LOST) {
+                self.sent.pop_front();
+            }
+            if self.sent.len() <= max { return }
+            sleep(Duration::from_millis(1));
+        }
+    }
+}
+
 async fn pop_futs(futs: &mut Vec<task::JoinHandle<()>>) {
     futs.pop().expect("Unable to pop of futures vec during mass_scan()!").await.unwrap();
 }
 
-async fn ping(ip: Ipv4Addr, data: &[u8]) {
-    trace!("Scanning IP \"{ip}\"");
-    let mut pack = ICMP_PACKET.to_vec();
+/// Scanner ping to `ip` carrying `data`, with the IP in place of the identifier and sequence
+fn probe(ip: Ipv4Addr, data: &[u8]) -> Vec<u8> {
+    let mut pack = vec![0; MTU];
+    let len = write_probe(&mut pack, ip, data);
+    pack.truncate(len);
+    pack
+}
 
-    pack[4..8].copy_from_slice(&ip.octets());
-    pack.append(&mut data.to_vec());
+/// Write the scanner ping to `ip` carrying `data` into `buf`, returning its length
+fn write_probe(buf: &mut [u8], ip: Ipv4Addr, data: &[u8]) -> usize {
+    let len = ICMP_PACKET.len() + data.len();
+    buf[..ICMP_PACKET.len()].copy_from_slice(&ICMP_PACKET);
+    buf[4..8].copy_from_slice(&ip.octets());
+    buf[ICMP_PACKET.len()..len].copy_from_slice(data);
 
-    let checksum = checksum(&pack);
-    pack[2] = checksum[0];
-    pack[3] = checksum[1];
+    let checksum = checksum(&buf[..len]);
+    buf[2] = checksum[0];
+    buf[3] = checksum[1];
+    len
+}
+
+async fn ping(ip: Ipv4Addr, data: &[u8]) {
+    trace!("Scanning IP \"{ip}\"");
+    let pack = probe(ip, data);
 
-    let mut socket = connect(IpAddr::V4(ip));
+    let socket = connect(IpAddr::V4(ip));
 
     match socket.send(&pack) {
         Ok(size) => if size != pack.len() { debug!("Sent {size} bytes of {} bytes to {ip}", pack.len()) },
@@ -148,19 +251,27 @@ async fn ping(ip: Ipv4Addr, data: &[u8]) {
     debug!("Ping packet sent to IP \"{ip}\"");
 }
 
-async fn listner(chan: mpsc::Receiver<()>) -> Vec<PingResponse> {
-    let socket = match IcmpSocket::connect(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))) {
+async fn listner(chan: mpsc::Receiver<()>, answered: Arc<AtomicUsize>) -> Vec<PingResponse> {
+    let sock = match socket::raw() {
         Err(err) => panic!("Unable to open listening socket: {err:?}"),
         Ok(sock) => sock,
     };
-    socket.set_read_timeout(Some(Duration::from_secs(1)));
+    if let Err(err) = filter::scanner(&sock) {
+        debug!("Unable to filter the listening socket: {err:?}");
+    }
+    let mut receiver = socket::Receiver::new(sock).expect("Unable to open listening socket");
     let mut handles = vec![];
     info!("Listner started");
     loop {
-        let mut response: [u8; RESPONSE_SIZE] = [0; RESPONSE_SIZE];
-        match socket.recv(&mut response) {
-            Ok(size) => handles.push(task::spawn(handler(response, size))),
-            Err(err) => trace!("Error reading a ping reply: {err:?}"),
+        let received = receiver.recv(Duration::from_secs(1), |packet| {
+            let mut response: [u8; RESPONSE_SIZE] = [0; RESPONSE_SIZE];
+            let size = packet.len().min(RESPONSE_SIZE);
+            response[..size].copy_from_slice(&packet[..size]);
+            handles.push(task::spawn(handler(response, packet.len())));
+            answered.fetch_add(1, Ordering::Relaxed);
+        });
+        if let Err(err) = received {
+            trace!("Error reading ping replies: {err:?}");
         }
         match chan.try_recv() {
             Err(err) => match err {
@@ -186,7 +297,13 @@ async fn listner(chan: mpsc::Receiver<()>) -> Vec<PingResponse> {
     pings
 }
 
-async fn handler(res: [u8; RESPONSE_SIZE], size: usize) -> PingResponse {
+async fn handler(res: [u8; RESPONSE_SIZE], _size: usize) -> PingResponse {
+    if res[20] != 0 {
+        // An ICMP error quoting the ping, whoever it was sent to is unreachable
+        let ip = Ipv4Addr::new(res[44], res[45], res[46], res[47]);
+        debug!("ICMP error {} pinging \"{ip}\"", res[20]);
+        return PingResponse { finish: Instant::now(), corrupt: false, small: false, unreachable: true, ip }
+    }
     let data = res[28..].to_vec();
     let ip = Ipv4Addr::new(res[24], res[25], res[26], res[27]);
     info!("Handling response from \"{ip}\"");
@@ -198,7 +315,7 @@ async fn handler(res: [u8; RESPONSE_SIZE], size: usize) -> PingResponse {
         corrupt = true;
     }
     PingResponse {
-        finish: Instant::now(), corrupt, small, ip
+        finish: Instant::now(), corrupt, small, unreachable: false, ip
         //, data
     }
 }
@@ -208,3 +325,42 @@ impl Default for Scanner {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_destinations() {
+        let dst: Destination = serde_json::from_str(
+            r#"{"round_trip":{"secs":0,"nanos":5000000},"small":false,"ip":"192.0.2.1"}"#).unwrap();
+        assert_eq!(dst.state, State::Active);
+        let dst: Destination = serde_json::from_str(
+            r#"{"round_trip":{"secs":0,"nanos":5000000},"small":false,"ip":"192.0.2.1","state":"Candidate"}"#).unwrap();
+        assert_eq!(dst.state, State::Candidate);
+    }
+
+    #[test]
+    fn in_flight() {
+        let answered = Arc::new(AtomicUsize::new(0));
+        let mut in_flight = InFlight { sent: VecDeque::new(), answered: answered.clone() };
+        in_flight.sent(4);
+        answered.fetch_add(2, Ordering::Relaxed);
+        in_flight.wait(2);
+        assert_eq!(in_flight.sent.len(), 2);
+        // Probes left unanswered stop counting once they're lost
+        in_flight.sent[0] = Instant::now().checked_sub(LOST).unwrap();
+        in_flight.wait(1);
+        assert_eq!(in_flight.sent.len(), 1);
+    }
+
+    #[test]
+    fn unreachable() {
+        let mut res = [0; RESPONSE_SIZE];
+        res[20] = 3;
+        res[44..48].copy_from_slice(&[192, 0, 2, 1]);
+        let ping = crate::runtime().block_on(handler(res, RESPONSE_SIZE));
+        assert!(ping.unreachable && !ping.corrupt);
+        assert_eq!(ping.ip, Ipv4Addr::new(192, 0, 2, 1));
+    }
+}
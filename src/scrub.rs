@@ -0,0 +1,46 @@
+use std::time::SystemTime;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use crate::json;
+
+/// Tally kept by the scrubber of a `PingStore`
+#[derive(Deserialize, Serialize, Default, Debug)]
+pub struct ScrubReport {
+    /// Full walks over every block
+    pub passes: usize,
+    /// Blocks checked over all passes
+    pub checked: usize,
+    /// Blocks that had at least one replica corrected or restored
+    pub repaired: usize,
+    /// Addresses without a quorum to repair from in the last pass
+    pub unrecoverable: Vec<usize>,
+    /// Seconds since the epoch when the last pass finished
+    pub finished: u64,
+}
+
+impl ScrubReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the results of a finished pass
+    pub fn pass(&mut self, checked: usize, repaired: usize, unrecoverable: Vec<usize>) {
+        self.passes += 1;
+        self.checked += checked;
+        self.repaired += repaired;
+        self.unrecoverable = unrecoverable;
+        self.finished = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_secs()).unwrap_or_default();
+    }
+
+    pub fn save(&self, file_name: &str) {
+        if let Err(err) = json::save(file_name, self) {
+            error!("Saving scrub report to file \"{file_name}\": {err:?}");
+        }
+    }
+
+    pub fn load(file_name: &str) -> Self {
+        json::load(file_name, "scrub report").unwrap_or_default()
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, RwLock};
+
+const SHARDS: usize = 64; // Independently locked shards, consecutive slots land in different ones
+
+/// Append-only physical slots spread over shards, so requests for different slots never wait
+/// on each other and the number of slots is read without taking any lock
+pub struct Slots<T> {
+    shards: Vec<RwLock<Vec<T>>>,
+    len: AtomicUsize,
+    grow: Mutex<()>,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(vec![])).collect(),
+            len: AtomicUsize::new(0),
+            grow: Mutex::new(()),
+        }
+    }
+}
+
+impl<T> Slots<T> {
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Add `value` as a new slot, returning its index
+    pub fn push(&self, value: T) -> usize {
+        let _grow = self.grow.lock().unwrap();
+        let slot = self.len.load(Ordering::Relaxed);
+        self.shards[slot % SHARDS].write().unwrap().push(value);
+        self.len.store(slot + 1, Ordering::Release);
+        slot
+    }
+
+    /// Run `f` on `slot`, only other readers and writers of its shard are held off
+    pub fn with<R>(&self, slot: usize, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.shards[slot % SHARDS].read().unwrap()[slot / SHARDS])
+    }
+
+    /// Run `f` on `slot` mutably, only other readers and writers of its shard are held off
+    pub fn update<R>(&self, slot: usize, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.shards[slot % SHARDS].write().unwrap()[slot / SHARDS])
+    }
+
+    /// `f` of every slot in order
+    pub fn map<R>(&self, mut f: impl FnMut(&T) -> R) -> Vec<R> {
+        (0..self.len()).map(|slot| self.with(slot, &mut f)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn concurrent() {
+        let slots = Arc::new(Slots::default());
+        let threads: Vec<_> = (0..8).map(|thread| {
+            let slots = slots.clone();
+            thread::spawn(move || (0..100).map(|i| slots.push(thread * 100 + i)).collect::<Vec<_>>())
+        }).collect();
+        let mut indices: Vec<usize> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        indices.sort();
+        assert_eq!(indices, (0..800).collect::<Vec<_>>());
+        assert_eq!(slots.len(), 800);
+
+        // Every value sits at the index it was pushed at
+        let values = slots.map(|value| *value);
+        assert!(indices.iter().all(|slot| slots.with(*slot, |value| *value) == values[*slot]));
+        let mut sorted = values.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..800).collect::<Vec<_>>());
+
+        slots.update(65, |value| *value = 1000);
+        assert_eq!(slots.with(65, |value| *value), 1000);
+        assert_eq!(slots.map(|value| *value)[65], 1000);
+    }
+}
@@ -0,0 +1,776 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, OnceLock},
+    time::Instant};
+
+use log::{trace, debug};
+use socket2::{Domain, Protocol, SockAddr, Type};
+use tokio::{sync::Notify, task, time};
+
+use crate::{ICMP_PACKET, SIZE, TIMEOUT, BATCH, buf::Buf, checksum, congestion, rto, runtime};
+#[cfg(not(feature = "uring"))]
+pub(crate) use mmsg::{Transport, Sender, Receiver};
+#[cfg(feature = "uring")]
+pub(crate) use crate::uring::{Transport, Sender, Receiver};
+
+const WINDOW: usize = 1024; // Echoes in flight at once when measuring throughput
+pub(crate) const RECV_BUFFER: usize = 1 << 24; // Bytes of replies the kernel queues until they're received
+pub(crate) const MTU: usize = 1500; // Bytes of the largest packet sent or received
+const IDENT: u16 = u16::from_be_bytes([ICMP_PACKET[4], ICMP_PACKET[5]]); // Identifier of store pings
+
+type Key = (IpAddr, u16, u16); // Source, identifier and sequence number of an echo reply
+type Reply = io::Result<(Instant, Buf)>; // When an echo reply was received with its data
+
+/// The raw ICMP transport every store ping goes through, with the echoes waited for on it
+struct Dispatcher {
+    transport: Transport,
+    waiting: Mutex<Waiting>,
+}
+
+/// Where the reply to an echo is left for its waiter, reused once the waiter is done with it
+#[derive(Default)]
+struct Mailbox {
+    reply: Mutex<Option<Reply>>,
+    delivered: Notify,
+    holding: AtomicBool, // Whether the echo still holds room in the congestion windows
+}
+
+impl Mailbox {
+    fn put(&self, res: Reply) {
+        *self.reply.lock().unwrap() = Some(res);
+        self.delivered.notify_one();
+    }
+
+    /// Give back the room the echo to `ip` holds in the congestion windows, if it still does
+    fn release(&self, ip: IpAddr) {
+        if self.holding.swap(false, Ordering::AcqRel) {
+            congestion::release(ip);
+        }
+    }
+
+    /// The reply once it's delivered
+    async fn take(&self) -> Reply {
+        loop {
+            let delivered = self.delivered.notified();
+            if let Some(res) = self.reply.lock().unwrap().take() { return res }
+            delivered.await;
+        }
+    }
+}
+
+/// Echoes waited for by key, each waiter with an id of its own as keys wrap around,
+/// with the mailboxes of the waiters done kept for the next ones
+#[derive(Default)]
+struct Waiting {
+    waiters: HashMap<Key, (u64, Arc<Mailbox>)>,
+    spare: Vec<Arc<Mailbox>>,
+    seq: u16,
+    id: u64,
+}
+
+impl Waiting {
+    /// Wait for an echo reply from `ip`, under a sequence number no echo is still waited for
+    /// with unless every one of them is, returning its key, waiter id and the mailbox of its reply
+    fn insert(&mut self, ip: IpAddr) -> (Key, u64, Arc<Mailbox>) {
+        let mut key = (ip, IDENT, self.seq);
+        for _ in 0..=u16::MAX {
+            key.2 = self.seq;
+            self.seq = self.seq.wrapping_add(1);
+            if !self.waiters.contains_key(&key) { break }
+        }
+        self.id += 1;
+        let mailbox = self.spare.pop().unwrap_or_default();
+        mailbox.holding.store(true, Ordering::Release);
+        self.waiters.insert(key, (self.id, mailbox.clone()));
+        (key, self.id, mailbox)
+    }
+
+    /// Stop the waiter `id` waiting for `key`, leaving whoever waits for it since alone,
+    /// its `mailbox` is kept for another waiter once nothing else refers to it
+    fn remove(&mut self, key: &Key, id: u64, mailbox: Arc<Mailbox>) {
+        if self.waiters.get(key).is_some_and(|(waiter, _)| *waiter == id) {
+            self.waiters.remove(key);
+        }
+        if Arc::strong_count(&mailbox) == 1 {
+            mailbox.reply.lock().unwrap().take();
+            self.spare.push(mailbox);
+        }
+    }
+
+    /// Hand the reply `res` to whoever waits for `key`, returning whether anyone did, the echo
+    /// gives back its room in the congestion windows right away as it's no longer in flight
+    fn deliver(&mut self, key: &Key, res: Reply) -> bool {
+        match self.waiters.remove(key) {
+            None => false,
+            Some((_, mailbox)) => {
+                mailbox.release(key.0);
+                mailbox.put(res);
+                true
+            },
+        }
+    }
+}
+
+/// The dispatcher, opening the transport and starting its send and receive loops on first use
+fn dispatcher() -> &'static Dispatcher {
+    static DISPATCHER: OnceLock<Dispatcher> = OnceLock::new();
+    if let Some(dispatcher) = DISPATCHER.get() { return dispatcher }
+    let mut opened = false;
+    let dispatcher = DISPATCHER.get_or_init(|| {
+        opened = true;
+        let transport = match Transport::open(batch()) {
+            Err(err) => panic!("Unable to open ICMP socket: {err:?}"),
+            Ok(transport) => transport,
+        };
+        Dispatcher { transport, waiting: Mutex::new(Waiting::default()) }
+    });
+    if opened {
+        dispatcher.transport.run(deliver, fail);
+    }
+    dispatcher
+}
+
+/// Hand the echo reply in the IP `packet` to whoever is waiting for it with the time it
+/// arrived, failing them early if it's an ICMP error quoting their ping, dropping the rest
+fn deliver(packet: Buf) {
+    let arrived = Instant::now();
+    let Some((key, res)) = parse(&packet) else { return };
+    if key.1 != IDENT { return }
+    if let Err(err) = &res {
+        debug!("Store ping to \"{}\" failed: {err}", key.0);
+    }
+    if !dispatcher().waiting.lock().unwrap().deliver(&key, res.map(|data| (arrived, data))) {
+        trace!("Dropping unexpected echo {key:?}");
+    }
+}
+
+/// Fail whoever waits for the reply to the store ping to `ip` with the sequence number `seq`
+/// with `err`, as the ping couldn't be sent
+fn fail(ip: IpAddr, seq: u16, err: io::Error) {
+    dispatcher().waiting.lock().unwrap().deliver(&(ip, IDENT, seq), Err(err));
+}
+
+/// An echo waited for, no longer waited for once dropped
+struct Waiter {
+    key: Key,
+    id: u64,
+    mailbox: Option<Arc<Mailbox>>, // Only taken when dropped
+    sent: Instant,
+}
+
+impl Waiter {
+    /// Wait for the reply to an echo to `ip` that took room in the congestion windows
+    fn new(ip: IpAddr) -> Self {
+        let (key, id, mailbox) = dispatcher().waiting.lock().unwrap().insert(ip);
+        Self { key, id, mailbox: Some(mailbox), sent: Instant::now() }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if let Some(mailbox) = self.mailbox.take() {
+            mailbox.release(self.key.0);
+            dispatcher().waiting.lock().unwrap().remove(&self.key, self.id, mailbox);
+        }
+    }
+}
+
+/// Echoes in flight to a destination and the generation of the data they carry
+#[derive(Default)]
+struct Circulating {
+    gen: u64,
+    waiters: VecDeque<Waiter>,
+}
+
+/// Echoes sent to a single destination through the shared socket, received in the order they
+/// were sent, every overwrite of the data circulating through it starts a new generation
+pub struct Socket {
+    ip: IpAddr,
+    sent: Mutex<Circulating>,
+    queued: Notify,
+}
+
+impl Socket {
+    pub fn new(ip: IpAddr) -> Self {
+        Self { ip, sent: Mutex::new(Circulating::default()), queued: Notify::new() }
+    }
+
+    /// Generation of the data circulating now, replies are received and echoed under it
+    pub fn generation(&self) -> u64 {
+        self.sent.lock().unwrap().gen
+    }
+
+    /// Queue an echo request carrying new `data` to be sent with the next batch, once the
+    /// congestion windows have room for it, `recv` returns the error if it can't be sent
+    pub async fn send(&self, data: &Buf) -> io::Result<usize> {
+        congestion::acquire(self.ip).await;
+        Ok(self.push(&mut self.sent.lock().unwrap(), data))
+    }
+
+    /// Queue an echo request carrying `data` back out in place of a reply of the generation
+    /// `gen` just received, which keeps the pings in flight the same so it takes its room in
+    /// the congestion windows without waiting for it, nothing is sent if the data was
+    /// overwritten since
+    pub fn echo(&self, gen: u64, data: &Buf) -> usize {
+        let mut sent = self.sent.lock().unwrap();
+        if sent.gen != gen { return 0 }
+        congestion::take(self.ip);
+        self.push(&mut sent, data)
+    }
+
+    fn push(&self, sent: &mut Circulating, data: &Buf) -> usize {
+        let waiter = Waiter::new(self.ip);
+        dispatcher().transport.send(self.ip, waiter.key.2, data);
+        sent.waiters.push_back(waiter);
+        self.queued.notify_waiters();
+        ICMP_PACKET.len() + data.len()
+    }
+
+    /// Stop waiting for the echoes in flight and start a new generation, their replies
+    /// carry data that is being overwritten
+    pub fn drain(&self) {
+        let mut sent = self.sent.lock().unwrap();
+        sent.waiters.clear();
+        sent.gen += 1;
+        self.queued.notify_waiters();
+    }
+
+    /// Data of the reply to the oldest echo of the generation `gen` still in flight, waiting
+    /// up to the global timeout for one to be sent if there's none, the echo is lost once the
+    /// retransmission timeout of the destination passes without a reply, and superseded
+    /// once the data was overwritten without one, the round trip is timed by when the reply
+    /// arrived rather than when it's received here
+    pub async fn recv(&self, gen: u64) -> io::Result<Buf> {
+        let next = async {
+            loop {
+                let queued = self.queued.notified();
+                {
+                    let mut sent = self.sent.lock().unwrap();
+                    if sent.gen != gen {
+                        return Err(io::Error::new(io::ErrorKind::Interrupted, "data was overwritten"))
+                    }
+                    if let Some(waiter) = sent.waiters.pop_front() { return Ok(waiter) }
+                }
+                queued.await;
+            }
+        };
+        let waiter = match unsafe { TIMEOUT } {
+            None => next.await?,
+            Some(timeout) => time::timeout(timeout, next).await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+        };
+        let timeout = rto::timeout(self.ip);
+        let deadline = waiter.sent + timeout;
+        let mailbox = waiter.mailbox.as_ref().unwrap();
+        match time::timeout_at(deadline.into(), mailbox.take()).await {
+            Err(_) => {
+                // An echo only found lost long after its deadline says nothing about the path now
+                if deadline.elapsed() < timeout {
+                    rto::backoff(self.ip);
+                    congestion::loss(self.ip);
+                }
+                Err(io::ErrorKind::TimedOut.into())
+            },
+            Ok(Err(err)) => Err(err),
+            Ok(Ok((arrived, data))) => {
+                rto::sample(self.ip, arrived.saturating_duration_since(waiter.sent));
+                congestion::ack(self.ip);
+                Ok(data)
+            },
+        }
+    }
+}
+
+/// Echoes per second bouncing `count` pings off `ip`, sending and receiving `batch`
+/// packets per syscall, with the number of pings lost, the congestion windows are
+/// bypassed to measure the transport alone
+pub fn throughput(ip: IpAddr, count: usize, batch: usize) -> (f64, usize) {
+    dispatcher().transport.set_batch(batch);
+    let sock = Arc::new(Socket::new(ip));
+    let data = Buf::copy(&[0x66; SIZE]);
+    runtime().block_on(async {
+        let start = Instant::now();
+        let mut lost = 0;
+        let mut left = count;
+        while left > 0 {
+            let window = left.min(WINDOW);
+            for _ in 0..window {
+                sock.echo(0, &data);
+            }
+            let replies: Vec<_> = (0..window).map(|_| {
+                let sock = sock.clone();
+                task::spawn(async move { sock.recv(0).await })
+            }).collect();
+            for reply in replies {
+                if !matches!(reply.await, Ok(Ok(_))) { lost += 1 }
+            }
+            left -= window;
+        }
+        ((count - lost) as f64 / start.elapsed().as_secs_f64(), lost)
+    })
+}
+
+/// Packets sent or received per syscall
+pub(crate) fn batch() -> usize {
+    unsafe { BATCH }.max(1)
+}
+
+/// A raw ICMP socket
+pub(crate) fn raw() -> io::Result<socket2::Socket> {
+    socket2::Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
+}
+
+/// Address to send raw ICMP to `ip` at
+pub(crate) fn addr(ip: IpAddr) -> SockAddr {
+    SocketAddr::new(ip, 0).into()
+}
+
+/// Write an echo request carrying `data` with the sequence number `seq` into `buf`, returning its length
+#[cfg_attr(not(feature = "uring"), allow(dead_code))] // Sent from the header and the data as they lie
+pub(crate) fn packet(buf: &mut [u8], seq: u16, data: &[u8]) -> usize {
+    let len = ICMP_PACKET.len() + data.len();
+    buf[..ICMP_PACKET.len()].copy_from_slice(&header(seq, data));
+    buf[ICMP_PACKET.len()..len].copy_from_slice(data);
+    len
+}
+
+/// Header of an echo request carrying `data` with the sequence number `seq`, checksummed over
+/// the data so the packet can be sent from the header and the data wherever they lie
+pub(crate) fn header(seq: u16, data: &[u8]) -> [u8; ICMP_PACKET.len()] {
+    let mut header = ICMP_PACKET;
+    header[6..8].copy_from_slice(&seq.to_be_bytes());
+    // The one's complement sum of the whole packet is that of the sums of its even length parts
+    let sum = [checksum(&header), checksum(data)].iter()
+        .map(|part| !u16::from_be_bytes(*part) as u32)
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    header[2..4].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    header
+}
+
+/// Source, identifier and sequence number of the echo reply in the IP `packet` with its data,
+/// sharing the storage of the packet, or those of the echo request an ICMP error quotes with the error
+fn parse(packet: &Buf) -> Option<(Key, io::Result<Buf>)> {
+    let header = (*packet.first()? as usize & 0x0f) * 4;
+    let icmp = packet.get(header..).filter(|icmp| icmp.len() >= 8)?;
+    match icmp[0] {
+        0 => {
+            let src = packet.get(12..16)?;
+            let src = IpAddr::V4(Ipv4Addr::new(src[0], src[1], src[2], src[3]));
+            let ident = u16::from_be_bytes([icmp[4], icmp[5]]);
+            let seq = u16::from_be_bytes([icmp[6], icmp[7]]);
+            Some(((src, ident, seq), Ok(packet.skip(header + 8))))
+        },
+        kind @ (3 | 11) => { // Destination unreachable or time exceeded
+            let quoted = &icmp[8..];
+            let header = (*quoted.first()? as usize & 0x0f) * 4;
+            let dst = quoted.get(16..20)?;
+            let echo = quoted.get(header..).filter(|echo| echo.len() >= 8 && echo[0] == 8)?;
+            let dst = IpAddr::V4(Ipv4Addr::new(dst[0], dst[1], dst[2], dst[3]));
+            let ident = u16::from_be_bytes([echo[4], echo[5]]);
+            let seq = u16::from_be_bytes([echo[6], echo[7]]);
+            let err = match (kind, icmp[1]) {
+                (3, 0) => io::ErrorKind::NetworkUnreachable,
+                _ => io::ErrorKind::HostUnreachable,
+            };
+            Some(((dst, ident, seq), Err(io::Error::new(err, format!("ICMP error {kind} code {}", icmp[1])))))
+        },
+        _ => None,
+    }
+}
+
+/// Batched raw ICMP with sendmmsg and recvmmsg
+#[cfg(not(feature = "uring"))]
+mod mmsg {
+    use std::{
+        io::{self, IoSlice},
+        net::IpAddr,
+        os::fd::AsRawFd,
+        sync::{atomic::{AtomicUsize, Ordering}, Mutex},
+        time::Duration,
+        mem, ptr};
+
+    use log::{debug, error};
+    use socket2::SockAddr;
+    use tokio::{io::unix::AsyncFd, sync::Notify};
+
+    use super::{RECV_BUFFER, MTU, addr, batch, header, raw};
+    use crate::{ICMP_PACKET, buf::Buf, filter, runtime};
+
+    type Header = [u8; ICMP_PACKET.len()];
+    type Outgoing = (IpAddr, u16, SockAddr, Header, Buf); // Destination, sequence number, address, header and data of a ping
+
+    /// The shared non-blocking socket with the store pings queued to be sent on it, each as its
+    /// header and the data it carries, sent as they lie
+    pub struct Transport {
+        sock: AsyncFd<socket2::Socket>,
+        outgoing: Mutex<Vec<Outgoing>>,
+        queued: Notify,
+        batch: AtomicUsize, // Packets sent or received per syscall
+    }
+
+    impl Transport {
+        /// Open the socket, sending and receiving `batch` packets per syscall
+        pub fn open(batch: usize) -> io::Result<Self> {
+            let sock = raw()?;
+            sock.set_nonblocking(true)?;
+            if let Err(err) = sock.set_recv_buffer_size(RECV_BUFFER) {
+                debug!("Unable to grow the ICMP socket receive buffer: {err:?}");
+            }
+            if let Err(err) = filter::store(&sock) {
+                debug!("Unable to filter the ICMP socket: {err:?}");
+            }
+            let _rt = runtime().enter();
+            Ok(Self {
+                sock: AsyncFd::new(sock)?, outgoing: Mutex::new(vec![]), queued: Notify::new(),
+                batch: AtomicUsize::new(batch.max(1)),
+            })
+        }
+
+        /// Send and receive `batch` packets per syscall from now on
+        pub fn set_batch(&self, batch: usize) {
+            self.batch.store(batch.max(1), Ordering::Relaxed);
+        }
+
+        fn batch(&self) -> usize {
+            self.batch.load(Ordering::Relaxed)
+        }
+
+        /// Queue a store ping to `ip` with the sequence number `seq` carrying `data`, sent
+        /// from where the data lies
+        pub fn send(&self, ip: IpAddr, seq: u16, data: &Buf) {
+            let header = header(seq, data);
+            self.outgoing.lock().unwrap().push((ip, seq, addr(ip), header, data.clone()));
+            self.queued.notify_one();
+        }
+
+        /// Start sending the queued pings and handing the packets received to `deliver`,
+        /// the pings that can't be sent are handed to `fail` by destination and sequence number
+        pub fn run(&'static self, deliver: fn(Buf), fail: fn(IpAddr, u16, io::Error)) {
+            runtime().spawn(self.transmit(fail));
+            runtime().spawn(self.receive(deliver));
+        }
+
+        /// Send the queued packets, up to a batch of them per syscall
+        async fn transmit(&self, fail: fn(IpAddr, u16, io::Error)) {
+            loop {
+                self.queued.notified().await;
+                loop {
+                    let (keys, packets): (Vec<_>, Vec<_>) = {
+                        let mut outgoing = self.outgoing.lock().unwrap();
+                        let count = outgoing.len().min(self.batch());
+                        outgoing.drain(..count)
+                            .map(|(ip, seq, addr, header, data)| ((ip, seq), (addr, header, data)))
+                            .unzip()
+                    };
+                    if packets.is_empty() { break }
+                    self.send_all(&keys, &packets, fail).await;
+                }
+            }
+        }
+
+        /// Send `packets`, handing the destination and sequence number of those that fail to `fail`
+        async fn send_all(
+            &self, keys: &[(IpAddr, u16)], packets: &[(SockAddr, Header, Buf)], fail: fn(IpAddr, u16, io::Error)
+        ) {
+            let packets: Vec<_> = packets.iter().map(|(addr, header, data)| (addr, [&header[..], data])).collect();
+            let mut sent = 0;
+            while sent < packets.len() {
+                let Ok(mut ready) = self.sock.writable().await else { return };
+                match ready.try_io(|sock| match &packets[sent..] {
+                    [(addr, parts)] => sock.get_ref().send_to_vectored(&parts.map(IoSlice::new), addr).map(|_| 1),
+                    packets => send_batch(sock.get_ref(), packets),
+                }) {
+                    Ok(Ok(count)) => sent += count,
+                    Ok(Err(err)) => {
+                        debug!("Unable to send store ping: {err:?}");
+                        let (ip, seq) = keys[sent];
+                        fail(ip, seq, err);
+                        sent += 1;
+                    },
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        async fn recv(&self, bufs: &mut [Buf]) -> io::Result<usize> {
+            loop {
+                let mut ready = self.sock.readable().await?;
+                match ready.try_io(|sock| match &mut *bufs {
+                    [buf] => recv_one(sock.get_ref(), buf.make_mut()),
+                    bufs => recv_batch(sock.get_ref(), bufs.iter_mut().map(Buf::make_mut)),
+                }) {
+                    Ok(res) => return res,
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        /// Hand every packet received to `deliver` in the pooled buffer it was received into,
+        /// receiving up to a batch of them per syscall
+        async fn receive(&self, deliver: fn(Buf)) {
+            let mut bufs = vec![];
+            loop {
+                bufs.resize_with(self.batch(), || Buf::with_capacity(MTU));
+                match self.recv(&mut bufs).await {
+                    Err(err) => error!("Receiving on the ICMP socket: {err:?}"),
+                    Ok(count) => bufs.drain(..count).for_each(deliver),
+                }
+            }
+        }
+    }
+
+    /// Packets built in place and sent in batches on a blocking raw socket
+    pub struct Sender {
+        sock: socket2::Socket,
+        packets: Vec<(SockAddr, Buf)>,
+    }
+
+    impl Sender {
+        pub fn new(sock: socket2::Socket) -> io::Result<Self> {
+            Ok(Self { sock, packets: vec![] })
+        }
+
+        /// Queue the packet `build` writes to `ip`, `build` returning its length
+        pub fn push(&mut self, ip: IpAddr, build: impl FnOnce(&mut [u8]) -> usize) {
+            let mut buf = Buf::zeroed(MTU);
+            let len = build(buf.make_mut());
+            buf.make_mut().truncate(len);
+            self.packets.push((addr(ip), buf));
+        }
+
+        /// Send the queued packets with as few sendmmsg calls as possible
+        pub fn flush(&mut self) {
+            let mut sent = 0;
+            let packets: Vec<_> = self.packets.iter().map(|(addr, packet)| (addr, [&packet[..], &[]])).collect();
+            while sent < packets.len() {
+                match send_batch(&self.sock, &packets[sent..]) {
+                    Ok(count) => sent += count,
+                    Err(err) => {
+                        debug!("Unable to send a batch of packets: {err:?}");
+                        sent += 1;
+                    },
+                }
+            }
+            self.packets.clear();
+        }
+    }
+
+    /// Packets received in batches on a blocking raw socket
+    pub struct Receiver {
+        sock: socket2::Socket,
+        bufs: Vec<Vec<u8>>,
+    }
+
+    impl Receiver {
+        pub fn new(sock: socket2::Socket) -> io::Result<Self> {
+            Ok(Self { sock, bufs: (0..batch()).map(|_| Vec::with_capacity(MTU)).collect() })
+        }
+
+        /// Hand the packets received to `deliver`, waiting up to `timeout` for the first one
+        pub fn recv(&mut self, timeout: Duration, mut deliver: impl FnMut(&[u8])) -> io::Result<()> {
+            self.sock.set_read_timeout(Some(timeout))?;
+            let count = match &mut self.bufs[..] {
+                [buf] => recv_one(&self.sock, buf)?,
+                bufs => recv_batch(&self.sock, bufs.iter_mut())?,
+            };
+            for buf in &self.bufs[..count] {
+                deliver(buf);
+            }
+            Ok(())
+        }
+    }
+
+    /// Send `packets`, each made of its parts one after the other, with a single sendmmsg,
+    /// returning how many went out
+    fn send_batch(sock: &socket2::Socket, packets: &[(&SockAddr, [&[u8]; 2])]) -> io::Result<usize> {
+        let mut iovs: Vec<[libc::iovec; 2]> = packets.iter()
+            .map(|(_, parts)| parts.map(|part| libc::iovec { iov_base: part.as_ptr() as *mut _, iov_len: part.len() }))
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = packets.iter().zip(iovs.iter_mut()).map(|((addr, _), iov)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr.as_ptr() as *mut _;
+            msg.msg_hdr.msg_namelen = addr.len();
+            msg.msg_hdr.msg_iov = iov.as_mut_ptr();
+            msg.msg_hdr.msg_iovlen = iov.len();
+            msg
+        }).collect();
+        match unsafe { libc::sendmmsg(sock.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            sent => Ok(sent as usize),
+        }
+    }
+
+    /// Receive a packet into the spare capacity of `buf` in place of what it held, returning
+    /// one for the packet received
+    fn recv_one(sock: &socket2::Socket, buf: &mut Vec<u8>) -> io::Result<usize> {
+        buf.clear();
+        let len = sock.recv(buf.spare_capacity_mut())?;
+        unsafe { buf.set_len(len) };
+        Ok(1)
+    }
+
+    /// Receive into the spare capacity of `bufs` in place of what they held with a single
+    /// recvmmsg, waiting only for the first packet, returning how many were received
+    fn recv_batch<'a>(sock: &socket2::Socket, bufs: impl Iterator<Item = &'a mut Vec<u8>>) -> io::Result<usize> {
+        let mut bufs: Vec<&mut Vec<u8>> = bufs.collect();
+        let mut iovs: Vec<libc::iovec> = bufs.iter_mut()
+            .map(|buf| {
+                buf.clear();
+                libc::iovec { iov_base: buf.as_mut_ptr() as *mut _, iov_len: buf.capacity() }
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().map(|iov| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }).collect();
+        let received = unsafe {
+            libc::recvmmsg(sock.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, libc::MSG_WAITFORONE, ptr::null_mut())
+        };
+        match received {
+            -1 => Err(io::Error::last_os_error()),
+            received => {
+                for (buf, msg) in bufs.iter_mut().zip(&msgs[..received as usize]) {
+                    unsafe { buf.set_len(msg.msg_len as usize) };
+                }
+                Ok(received as usize)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn generations() {
+        let sock = Socket::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let gen = sock.generation();
+        sock.drain();
+        assert_eq!(sock.generation(), gen + 1);
+        // Replies to data overwritten since are neither echoed nor waited for
+        assert_eq!(sock.echo(gen, &Buf::copy(&[0x66; SIZE])), 0);
+        let err = runtime().block_on(sock.recv(gen)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn timed() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10));
+        let sock = Socket::new(ip);
+        let queue = |sent: Instant| {
+            let mut waiter = Waiter::new(ip);
+            waiter.sent = sent;
+            let key = waiter.key;
+            sock.sent.lock().unwrap().waiters.push_back(waiter);
+            key
+        };
+        // The round trip is timed by when the reply arrived, however late it's received
+        let sent = Instant::now() - Duration::from_secs(2);
+        let key = queue(sent);
+        let reply = (sent + Duration::from_millis(100), Buf::copy(b"data"));
+        assert!(dispatcher().waiting.lock().unwrap().deliver(&key, Ok(reply)));
+        assert_eq!(&*runtime().block_on(sock.recv(0)).unwrap(), b"data");
+        let timeout = rto::timeout(ip);
+        assert_eq!(timeout, Duration::from_millis(300));
+
+        // An echo whose deadline passed long ago isn't backed off for, one whose just did is
+        queue(Instant::now() - timeout * 3);
+        assert_eq!(runtime().block_on(sock.recv(0)).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(rto::timeout(ip), timeout);
+        queue(Instant::now() - timeout);
+        assert_eq!(runtime().block_on(sock.recv(0)).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(rto::timeout(ip), timeout * 2);
+    }
+
+    #[test]
+    fn wrapping() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut waiting = Waiting { seq: u16::MAX, ..Waiting::default() };
+        let (old, old_id, old_mailbox) = waiting.insert(ip);
+        assert_eq!(old.2, u16::MAX);
+        for _ in 0..u16::MAX {
+            let (key, id, mailbox) = waiting.insert(ip);
+            waiting.remove(&key, id, mailbox);
+        }
+        // Every waiter since took the mailbox the one before left
+        assert_eq!(waiting.spare.len(), 1);
+        // The sequence number of an echo still waited for isn't handed out again
+        let (key, id, mailbox) = waiting.insert(ip);
+        assert_ne!(key, old);
+        assert!(waiting.deliver(&old, Ok((Instant::now(), Buf::copy(b"old")))));
+
+        // Once its reply came in the key is reused, and the old waiter giving up leaves it be
+        waiting.seq = u16::MAX;
+        let (reused, reused_id, reused_mailbox) = waiting.insert(ip);
+        assert_eq!(reused, old);
+        waiting.remove(&old, old_id, old_mailbox);
+        assert!(waiting.deliver(&reused, Ok((Instant::now(), Buf::copy(b"new")))));
+        assert_eq!(&*runtime().block_on(reused_mailbox.take()).unwrap().1, b"new");
+        assert_ne!(reused_id, id);
+        assert!(mailbox.reply.lock().unwrap().is_none());
+        waiting.remove(&key, id, mailbox);
+    }
+
+    #[test]
+    fn headers() {
+        // The header checksummed apart from the data it's sent with checksums the whole packet
+        for data in [&b"data"[..], &[0xff; SIZE], &[0; SIZE], b"odd"] {
+            let mut sent = header(7, data).to_vec();
+            sent.extend(data);
+            let sum = checksum(&sent);
+            assert!(sum == [0, 0] || sum == [0xff, 0xff], "{sum:?}");
+            let mut whole = vec![0; MTU];
+            let len = packet(&mut whole, 7, data);
+            whole.truncate(len);
+            assert_eq!(whole, sent);
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        let src = [198, 51, 100, 7];
+        let mut reply = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0, 198, 51, 100, 7, 192, 0, 2, 1];
+        let mut echo = vec![0; MTU];
+        let len = packet(&mut echo, 42, b"data");
+        echo.truncate(len);
+        echo[0] = 0;
+        reply.extend(&echo);
+        let (key, res) = parse(&Buf::copy(&reply)).unwrap();
+        assert_eq!(key, (IpAddr::V4(Ipv4Addr::from(src)), IDENT, 42));
+        assert_eq!(&*res.unwrap(), b"data");
+
+        // An error quoting the ping fails whoever waits for its reply
+        let mut request = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0, 192, 0, 2, 1, 198, 51, 100, 7];
+        echo[0] = 8;
+        request.extend(&echo[..8]);
+        let mut error = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0, 203, 0, 113, 9, 192, 0, 2, 1, 3, 1, 0, 0, 0, 0, 0, 0];
+        error.extend(&request);
+        let (key, res) = parse(&Buf::copy(&error)).unwrap();
+        assert_eq!(key, (IpAddr::V4(Ipv4Addr::from(src)), IDENT, 42));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::HostUnreachable);
+        assert!(parse(&Buf::copy(&error[..40])).is_none());
+    }
+
+    #[test]
+    fn failed() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut waiting = Waiting::default();
+        let (key, _, mailbox) = waiting.insert(ip);
+        assert!(mailbox.holding.load(Ordering::Acquire));
+        let err = io::Error::from(io::ErrorKind::HostUnreachable);
+        assert!(waiting.deliver(&key, Err(err)));
+        // The room it held is given back as soon as it's answered, not once it's received
+        assert!(!mailbox.holding.load(Ordering::Acquire));
+        let res = runtime().block_on(mailbox.take());
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::HostUnreachable);
+        // A late reply finds nobody waiting
+        assert!(!waiting.deliver(&key, Ok((Instant::now(), Buf::copy(b"late")))));
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, Arc},
+    net::IpAddr};
+
+use log::info;
+use crate::lifecycle::{State, Event};
+
+/// Reply statistics for a single destination, used to judge its reputation
+#[derive(Default, Clone, Debug)]
+pub struct Stats {
+    /// Replies carrying the agreed upon payload
+    pub good: usize,
+    /// Good replies that arrived after the read quorum was reached
+    pub late: usize,
+    /// Replies with a payload differing from the quorum
+    pub sus: usize,
+    /// Receives that failed or timed out
+    pub lost: usize,
+    /// Lifecycle state the events so far have driven the destination to
+    pub state: State,
+}
+
+/// Shared statistics for every destination in a store
+#[derive(Default, Clone)]
+pub struct Reputation(Arc<Mutex<HashMap<IpAddr, Stats>>>);
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `f` on the statistics of `ip`, creating them if needed
+    pub fn update<F: FnOnce(&mut Stats)>(&self, ip: IpAddr, f: F) {
+        f(self.0.lock().unwrap().entry(ip).or_default());
+    }
+
+    /// Count `event` for `ip` and move it along its lifecycle
+    pub fn record(&self, ip: IpAddr, event: Event) {
+        self.update(ip, |stats| {
+            match event {
+                Event::Agreed => stats.good += 1,
+                Event::Diverged => stats.sus += 1,
+                Event::Lost => stats.lost += 1,
+                Event::Scanned(_) => (),
+            }
+            let state = stats.state.next(event);
+            if state != stats.state {
+                info!("Destination \"{ip}\" went from {:?} to {state:?}", stats.state);
+                stats.state = state;
+            }
+        });
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Stats {
+        self.0.lock().unwrap().get(ip).cloned().unwrap_or_default()
+    }
+
+    pub fn state(&self, ip: &IpAddr) -> State {
+        self.get(ip).state
+    }
+
+    pub fn set_state(&self, ip: IpAddr, state: State) {
+        self.update(ip, |stats| stats.state = state);
+    }
+}
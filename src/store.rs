@@ -1,14 +1,12 @@
 use std::{
-    fs::OpenOptions,
-    io::{Write, Read},
-    str::from_utf8,
     net::Ipv4Addr,
     vec::Vec};
 
-use log::{trace, debug, error};
+use log::error;
 use serde::{Deserialize, Serialize};
 use crate::{
     scanner::Destination,
+    json,
     Scanner};
 
 #[derive(Deserialize, Serialize)]
@@ -26,56 +24,21 @@ impl IPStore {
         Self { dsts: scanner.dsts.clone(), dead: scanner.dead.clone() }
     }
 
+    /// Save the store into `file_name` atomically, so a reader never sees it half written
     pub fn save(&self, file_name: &str) {
-        let mut file = OpenOptions::new()
-            .write(true).create(true)
-            .open(file_name).unwrap();
-        match serde_json::to_string(self) {
-            Err(err) => {
-                error!("Serializing IPStore for saving to file: {:?}", err);
-            },
-            Ok(out) => {
-                match file.write_all(out.as_bytes()) {
-                    Err(err) => error!(
-                        "Saving IPStore to file \"{}\": {:?}",
-                        file_name, err),
-                    Ok(ok) => ok
-                };
-            },
-        };
-    }
-
-    pub fn load(file_name: &str) -> Self {
-        let mut input: Vec<u8> = vec![];
-
-        match OpenOptions::new().read(true).open(file_name) {
-            Err(err) => {
-                debug!(
-                    "Opening IPStore save file \"{}\": {:?}",
-                    file_name, err);
-                return IPStore::new();
-            },
-
-            Ok(mut file) => match file.read_to_end(&mut input) {
-                Err(err) => {
-                    error!(
-                        "Reading IPStore save file \"{}\": {:?}",
-                        file_name, err);
-                    return IPStore::new();
-                },
-                Ok(len) => trace!("Read {} bytes from IPStore save file", len),
-            }
+        if let Err(err) = json::save(file_name, self) {
+            error!("Saving IPStore to file \"{file_name}\": {err:?}");
         }
+    }
 
-        let data = from_utf8(&input).unwrap();
+    /// Load the store saved in `file_name`, none if there's no such file or it's unreadable
+    pub fn read(file_name: &str) -> Option<Self> {
+        json::load(file_name, "IPStore save file")
+    }
 
-        match serde_json::from_str(data) {
-            Err(err) => {
-                error!("Loading IPStore: {:?}", err);
-                IPStore::new()
-            },
-            Ok(store) => store,
-        }
+    /// Load the store saved in `file_name`, an empty one if there's none or it's unreadable
+    pub fn load(file_name: &str) -> Self {
+        Self::read(file_name).unwrap_or_default()
     }
 }
 
@@ -0,0 +1,119 @@
+use std::{io, net::IpAddr};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use crate::{json, SIZE};
+
+pub const VERSION: u32 = 1;
+/// Physical slots at the start of a store kept for the superblock
+pub const RESERVED: usize = 8;
+
+/// Description of a volume, kept both locally and in the reserved slots of the volume
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct Superblock {
+    pub uuid: String,
+    pub version: u32,
+    /// Bytes of data in every block
+    pub block_size: usize,
+    /// Logical blocks in the volume
+    pub blocks: usize,
+    /// Replicas of every block
+    pub copies: usize,
+    pub read_quorum: usize,
+    pub write_quorum: usize,
+    /// Checksum of the placement map, the destinations of every slot in order
+    pub dsts: u64,
+    /// Fingerprint of the key blocks are encrypted with, zero if they aren't
+    #[serde(default)]
+    pub key: u64,
+}
+
+impl Superblock {
+    pub fn new(blocks: usize, copies: usize, read_quorum: usize, write_quorum: usize, dsts: u64) -> Self {
+        let id: u128 = rand::random();
+        let hex = format!("{id:032x}");
+        Self {
+            uuid: format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]),
+            version: VERSION, block_size: SIZE,
+            blocks, copies, read_quorum, write_quorum, dsts, key: 0,
+        }
+    }
+
+    /// Serialize into the blocks to store in the reserved slots, length prefixed and zero padded
+    /// to fill every one of them, so none is left without data circulating
+    pub fn encode(&self) -> io::Result<Vec<Vec<u8>>> {
+        let json = serde_json::to_vec(self)?;
+        let mut data = (json.len() as u32).to_le_bytes().to_vec();
+        data.extend(json);
+        if data.len() > RESERVED * SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "superblock doesn't fit in the reserved slots"));
+        }
+        data.resize(RESERVED * SIZE, 0);
+        Ok(data.chunks(SIZE).map(|chunk| chunk.to_vec()).collect())
+    }
+
+    /// Deserialize from the contents of the reserved slots
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let Some((len, data)) = data.split_first_chunk::<4>() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated superblock"));
+        };
+        let len = u32::from_le_bytes(*len) as usize;
+        if len > data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated superblock"));
+        }
+        Ok(serde_json::from_slice(&data[..len])?)
+    }
+
+    pub fn save(&self, file_name: &str) {
+        if let Err(err) = json::save(file_name, self) {
+            error!("Saving superblock to file \"{file_name}\": {err:?}");
+        }
+    }
+
+    pub fn load(file_name: &str) -> Option<Self> {
+        json::load(file_name, "superblock file")
+    }
+}
+
+/// Stable (FNV-1a) checksum of a destination list continuing from `hash`,
+/// start from `checksum(0, &[])`
+pub fn checksum(hash: u64, ips: &[IpAddr]) -> u64 {
+    let mut hash = if hash == 0 { 0xcbf29ce484222325 } else { hash };
+    for ip in ips {
+        let octets = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        for byte in octets {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let superblock = Superblock::new(1024, 7, 4, 4, checksum(0, &["192.0.2.1".parse().unwrap()]));
+        let blocks = superblock.encode().unwrap();
+        assert!(blocks.len() == RESERVED && blocks.iter().all(|block| block.len() == SIZE));
+        let data = blocks.concat();
+        assert_eq!(Superblock::decode(&data).unwrap(), superblock);
+
+        for len in [0, 3, 4, SIZE] {
+            assert_eq!(Superblock::decode(&data[..len]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        let ips: Vec<IpAddr> = vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()];
+        assert_eq!(checksum(checksum(0, &ips[..1]), &ips[1..]), checksum(0, &ips));
+        assert_ne!(checksum(0, &ips), checksum(0, &[ips[1], ips[0]]));
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{atomic::{AtomicUsize, Ordering}, Mutex, OnceLock},
+    time::Duration,
+    mem, thread};
+
+use io_uring::{cqueue, opcode, types, IoUring};
+use log::{debug, error};
+use socket2::SockAddr;
+use tokio::{io::unix::AsyncFd, sync::Notify};
+
+use crate::{buf::Buf, filter, runtime, socket::{RECV_BUFFER, MTU, addr, batch, packet, raw}};
+
+type Failed = Vec<(IpAddr, u16, io::Error)>;
+
+const SLOTS: usize = 1024; // Packets in flight at once on a ring
+const CANCEL: u64 = u64::MAX; // User data of the request cancelling every other one
+const ZEROCOPY: u64 = 1 << 16; // User data flag of the sends made straight from a registered buffer
+
+/// An io_uring on `fd` with a fixed set of registered packet buffers, each sent from or received into in place
+struct Ring {
+    ring: IoUring,
+    fd: RawFd,
+    bufs: Vec<[u8; MTU]>,
+    iovecs: Vec<libc::iovec>,
+    msgs: Vec<libc::msghdr>,
+    addrs: Vec<SockAddr>,
+    zerocopy: bool, // Whether the socket takes sends from registered buffers, or sendmsg copies them
+    free: Vec<u16>,
+    queued: usize,
+    tags: Vec<Option<(IpAddr, u16)>>, // Destination and sequence number of the store ping sent from each buffer
+    resend: Vec<bool>, // Buffers to send again copied once the kernel let go of them
+    failed: Failed,
+}
+
+// The raw pointers only point into the ring's own buffers
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(fd: RawFd) -> io::Result<Self> {
+        let ring = IoUring::new(SLOTS as u32)?;
+        let mut bufs = vec![[0; MTU]; SLOTS];
+        let iovecs: Vec<libc::iovec> = bufs.iter_mut()
+            .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut _, iov_len: MTU })
+            .collect();
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+        Ok(Self {
+            ring,
+            fd,
+            bufs,
+            iovecs,
+            msgs: vec![unsafe { mem::zeroed() }; SLOTS],
+            addrs: vec![addr(IpAddr::V4(Ipv4Addr::UNSPECIFIED)); SLOTS],
+            zerocopy: true,
+            free: (0..SLOTS as u16).rev().collect(),
+            queued: 0,
+            tags: vec![None; SLOTS],
+            resend: vec![false; SLOTS],
+            failed: vec![],
+        })
+    }
+
+    /// A free buffer, reaping the sends completed if there's none, and waiting for one to
+    /// complete if there's still none and `wait`
+    fn slot(&mut self, wait: bool) -> io::Result<Option<u16>> {
+        loop {
+            if let Some(slot) = self.free.pop() { return Ok(Some(slot)) }
+            self.submit(wait as usize)?;
+            if !wait && self.free.is_empty() { return Ok(None) }
+        }
+    }
+
+    /// Queue the packet `build` writes into the free buffer `slot` to `ip`, submitting every
+    /// `batch` packets, a send tagged with its sequence number is recorded as failed if it
+    /// doesn't go out
+    fn send(&mut self, slot: u16, ip: IpAddr, tag: Option<u16>, batch: usize, build: impl FnOnce(&mut [u8]) -> usize) -> io::Result<()> {
+        let i = slot as usize;
+        self.tags[i] = tag.map(|seq| (ip, seq));
+        self.iovecs[i].iov_len = build(&mut self.bufs[i]);
+        self.addrs[i] = addr(ip);
+        if let Err(err) = self.queue(slot) {
+            self.free.push(slot);
+            return Err(err);
+        }
+        if self.queued >= batch {
+            self.submit(0)?;
+        }
+        Ok(())
+    }
+
+    /// Queue sending the packet in the buffer `slot`, straight from the registered buffer where
+    /// the socket supports it, the buffer then stays in flight until the kernel is done with it
+    fn queue(&mut self, slot: u16) -> io::Result<()> {
+        let i = slot as usize;
+        let entry = match self.zerocopy {
+            true => opcode::SendZc::new(types::Fd(self.fd), self.bufs[i].as_ptr(), self.iovecs[i].iov_len as u32)
+                .buf_index(Some(slot))
+                .dest_addr(self.addrs[i].as_ptr() as *const _)
+                .dest_addr_len(self.addrs[i].len())
+                .build().user_data(slot as u64 | ZEROCOPY),
+            false => {
+                let msg = &mut self.msgs[i];
+                msg.msg_name = self.addrs[i].as_ptr() as *mut _;
+                msg.msg_namelen = self.addrs[i].len();
+                msg.msg_iov = &mut self.iovecs[i];
+                msg.msg_iovlen = 1;
+                opcode::SendMsg::new(types::Fd(self.fd), msg).build().user_data(slot as u64)
+            },
+        };
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("submission queue full"))?;
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Submit the queued sends, waiting for `want` of them to complete, and free the buffers of
+    /// the ones that did
+    fn submit(&mut self, want: usize) -> io::Result<()> {
+        self.ring.submit_and_wait(want)?;
+        self.queued = 0;
+        let completed: Vec<(u64, i32, u32)> = self.ring.completion()
+            .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+            .collect();
+        for (data, res, flags) in completed {
+            if data == CANCEL { continue }
+            let slot = data as u16;
+            let i = slot as usize;
+            if res == -libc::EOPNOTSUPP && data & ZEROCOPY != 0 {
+                if self.zerocopy {
+                    debug!("Zero copy sends unsupported, copying packets out of the registered buffers");
+                    self.zerocopy = false;
+                }
+                self.resend[i] = true;
+            } else if res < 0 {
+                self.fail(slot, io::Error::from_raw_os_error(-res));
+            }
+            if cqueue::more(flags) { continue }
+            // The buffer still holds the packet, resend it copied once the kernel let go of it
+            if mem::take(&mut self.resend[i]) {
+                match self.queue(slot) {
+                    Ok(()) => continue,
+                    Err(err) => self.fail(slot, err),
+                }
+            }
+            self.free.push(slot);
+        }
+        if self.queued > 0 {
+            self.ring.submit()?;
+            self.queued = 0;
+        }
+        Ok(())
+    }
+
+    /// Record the send from the buffer `slot` as failed with `err`
+    fn fail(&mut self, slot: u16, err: io::Error) {
+        debug!("Unable to send packet: {err:?}");
+        if let Some((ip, seq)) = self.tags[slot as usize].take() {
+            self.failed.push((ip, seq, err));
+        }
+    }
+
+    /// Sends still in flight
+    fn sending(&self) -> usize {
+        SLOTS - self.free.len()
+    }
+
+    /// Hand the packets received to `deliver`, keeping a read armed on every free buffer and
+    /// waiting up to `timeout` for the first packet
+    fn recv(&mut self, timeout: Option<Duration>, mut deliver: impl FnMut(&[u8])) -> io::Result<()> {
+        for slot in self.free.drain(..) {
+            let buf = self.bufs[slot as usize].as_mut_ptr();
+            let entry = opcode::ReadFixed::new(types::Fd(self.fd), buf, MTU as u32, slot).build().user_data(slot as u64);
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| io::Error::other("submission queue full"))?;
+        }
+        let submitted = match timeout {
+            None => self.ring.submit_and_wait(1),
+            Some(timeout) => {
+                let timespec = types::Timespec::from(timeout);
+                self.ring.submitter().submit_with_args(1, &types::SubmitArgs::new().timespec(&timespec))
+            },
+        };
+        match submitted {
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ETIME | libc::EINTR)) => (),
+            Err(err) => return Err(err),
+            Ok(_) => (),
+        }
+        let Self { ring, bufs, free, .. } = self;
+        for cqe in ring.completion() {
+            if cqe.user_data() == CANCEL { continue }
+            let slot = cqe.user_data() as u16;
+            match cqe.result() {
+                len if len >= 0 => deliver(&bufs[slot as usize][..len as usize]),
+                err => debug!("Unable to receive packet: {:?}", io::Error::from_raw_os_error(-err)),
+            }
+            free.push(slot);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Ring {
+    /// Cancel every request still in flight and wait for them all to complete before the
+    /// buffers they read into or send from are freed, leaking the buffers if that fails
+    fn drop(&mut self) {
+        let cancel = opcode::AsyncCancel2::new(types::CancelBuilder::any()).build().user_data(CANCEL);
+        if self.free.len() < SLOTS && unsafe { self.ring.submission().push(&cancel) }.is_err() {
+            error!("Unable to cancel the requests in flight");
+        }
+        while self.free.len() < SLOTS {
+            match self.ring.submit_and_wait(1) {
+                Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(err) => {
+                    error!("Waiting for the requests in flight to complete: {err:?}");
+                    return mem::forget(mem::take(&mut self.bufs));
+                },
+                Ok(_) => (),
+            }
+            for cqe in self.ring.completion() {
+                if cqe.user_data() != CANCEL && !cqueue::more(cqe.flags()) {
+                    self.free.push(cqe.user_data() as u16);
+                }
+            }
+        }
+    }
+}
+
+/// The sending ring with the store pings waiting for a free buffer
+struct Sending {
+    ring: Ring,
+    backlog: VecDeque<(IpAddr, u16, Buf)>,
+}
+
+/// The shared raw socket with store pings written straight into registered buffers of a sending
+/// ring, and replies read into those of a receiving one
+pub struct Transport {
+    sock: socket2::Socket,
+    sending: Mutex<Sending>,
+    completed: AsyncFd<OwnedFd>, // Signalled as sends complete
+    queued: Notify,
+    batch: AtomicUsize, // Packets queued per submission
+    fail: OnceLock<fn(IpAddr, u16, io::Error)>,
+}
+
+impl Transport {
+    /// Open the socket, submitting the store pings `batch` at a time
+    pub fn open(batch: usize) -> io::Result<Self> {
+        let sock = raw()?;
+        if let Err(err) = sock.set_recv_buffer_size(RECV_BUFFER) {
+            debug!("Unable to grow the ICMP socket receive buffer: {err:?}");
+        }
+        if let Err(err) = filter::store(&sock) {
+            debug!("Unable to filter the ICMP socket: {err:?}");
+        }
+        let ring = Ring::new(sock.as_raw_fd())?;
+        let completed = match unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        ring.ring.submitter().register_eventfd(completed.as_raw_fd())?;
+        let _rt = runtime().enter();
+        Ok(Self {
+            sock, sending: Mutex::new(Sending { ring, backlog: VecDeque::new() }),
+            completed: AsyncFd::new(completed)?, queued: Notify::new(),
+            batch: AtomicUsize::new(batch.max(1)), fail: OnceLock::new(),
+        })
+    }
+
+    /// Submit `batch` store pings at a time from now on
+    pub fn set_batch(&self, batch: usize) {
+        self.batch.store(batch.max(1), Ordering::Relaxed);
+    }
+
+    /// Queue a store ping to `ip` with the sequence number `seq` carrying `data`, it waits in
+    /// the backlog if every buffer is in flight rather than block for one
+    pub fn send(&self, ip: IpAddr, seq: u16, data: &Buf) {
+        let batch = self.batch.load(Ordering::Relaxed);
+        let failed = {
+            let mut sending = self.sending.lock().unwrap();
+            let Sending { ring, backlog } = &mut *sending;
+            let slot = match backlog.is_empty() {
+                true => ring.slot(false),
+                false => Ok(None),
+            };
+            let sent = match slot {
+                Ok(Some(slot)) => ring.send(slot, ip, Some(seq), batch, |buf| packet(buf, seq, data)),
+                Ok(None) => {
+                    backlog.push_back((ip, seq, data.clone()));
+                    Ok(())
+                },
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                debug!("Unable to send store ping: {err:?}");
+                ring.failed.push((ip, seq, err));
+            }
+            mem::take(&mut ring.failed)
+        };
+        self.fail(failed);
+        self.queued.notify_one();
+    }
+
+    /// Submit the queued pings and those of the backlog that now fit, returning the ones that failed
+    fn submit(&self) -> Failed {
+        let mut sending = self.sending.lock().unwrap();
+        let Sending { ring, backlog } = &mut *sending;
+        while let Some((ip, seq, data)) = backlog.pop_front() {
+            let sent = match ring.slot(false) {
+                Ok(Some(slot)) => ring.send(slot, ip, Some(seq), SLOTS, |buf| packet(buf, seq, &data)),
+                Ok(None) => {
+                    backlog.push_front((ip, seq, data));
+                    break
+                },
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                ring.failed.push((ip, seq, err));
+            }
+        }
+        if let Err(err) = ring.submit(0) {
+            error!("Submitting store pings: {err:?}");
+        }
+        mem::take(&mut ring.failed)
+    }
+
+    /// Hand the store pings that couldn't be sent to the `fail` given to `run`
+    fn fail(&self, failed: Failed) {
+        if failed.is_empty() { return }
+        if let Some(fail) = self.fail.get() {
+            for (ip, seq, err) in failed {
+                fail(ip, seq, err);
+            }
+        }
+    }
+
+    /// Start submitting the queued pings and handing the packets received to `deliver`, copied
+    /// out of the registered buffers they're read into as those are read into again right away,
+    /// the pings that can't be sent are handed to `fail` by destination and sequence number
+    pub fn run(&'static self, deliver: fn(Buf), fail: fn(IpAddr, u16, io::Error)) {
+        self.fail.set(fail).ok();
+        runtime().spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = self.queued.notified() => (),
+                    ready = self.completed.readable() => match ready {
+                        Err(err) => return error!("Waiting for store pings to be sent: {err:?}"),
+                        Ok(mut ready) => {
+                            let mut count = 0u64;
+                            ready.try_io(|fd| match unsafe { libc::read(fd.as_raw_fd(), &mut count as *mut u64 as *mut _, 8) } {
+                                -1 => Err(io::Error::last_os_error()),
+                                _ => Ok(()),
+                            }).ok();
+                        },
+                    },
+                }
+                self.fail(self.submit());
+            }
+        });
+        thread::Builder::new().name("blues uring".into()).spawn(move || {
+            let mut ring = match Ring::new(self.sock.as_raw_fd()) {
+                Err(err) => return error!("Unable to set up receiving ring: {err:?}"),
+                Ok(ring) => ring,
+            };
+            loop {
+                if let Err(err) = ring.recv(None, |packet| deliver(Buf::copy(packet))) {
+                    error!("Receiving on the ICMP socket: {err:?}");
+                }
+            }
+        }).expect("Unable to spawn receiving thread");
+    }
+}
+
+/// Packets built in place in registered buffers and sent in batches on a raw socket
+pub struct Sender {
+    ring: Ring,
+    _sock: socket2::Socket, // Closed only once the ring dropped before it is done with it
+}
+
+impl Sender {
+    pub fn new(sock: socket2::Socket) -> io::Result<Self> {
+        Ok(Self { ring: Ring::new(sock.as_raw_fd())?, _sock: sock })
+    }
+
+    /// Queue the packet `build` writes to `ip`, `build` returning its length
+    pub fn push(&mut self, ip: IpAddr, build: impl FnOnce(&mut [u8]) -> usize) {
+        let sent = match self.ring.slot(true) {
+            Ok(Some(slot)) => self.ring.send(slot, ip, None, batch(), build),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = sent {
+            debug!("Unable to queue packet: {err:?}");
+        }
+    }
+
+    /// Send the queued packets, waiting for every one in flight to go out
+    pub fn flush(&mut self) {
+        if let Err(err) = self.ring.submit(self.ring.sending()) {
+            debug!("Unable to send a batch of packets: {err:?}");
+        }
+    }
+}
+
+/// Packets read into registered buffers on a raw socket
+pub struct Receiver {
+    ring: Ring,
+    _sock: socket2::Socket, // Closed only once the ring dropped before it is done with it
+}
+
+impl Receiver {
+    pub fn new(sock: socket2::Socket) -> io::Result<Self> {
+        Ok(Self { ring: Ring::new(sock.as_raw_fd())?, _sock: sock })
+    }
+
+    /// Hand the packets received to `deliver`, waiting up to `timeout` for the first one
+    pub fn recv(&mut self, timeout: Duration, deliver: impl FnMut(&[u8])) -> io::Result<()> {
+        self.ring.recv(Some(timeout), deliver)
+    }
+}